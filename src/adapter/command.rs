@@ -0,0 +1,101 @@
+use std::fmt;
+use std::io::{self, Read};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, ExitStatus, Stdio};
+use std::str;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// How often to check whether the command exited
+const WAIT_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Failure to run a command line tool to completion.
+#[derive(Debug)]
+pub enum CommandError {
+    Spawn { binary: String, source: io::Error },
+    Wait(io::Error),
+    Timeout(Duration),
+    Failed { status: ExitStatus, stderr: String },
+    InvalidOutput(str::Utf8Error),
+}
+
+/// Run `binary` with `args` and return its standard output, killing it and
+/// the processes it started when it takes longer than `timeout`.
+///
+/// Blocks until the command finished, async callers run it on a blocking thread.
+pub fn run(binary: &str, args: &[&str], timeout: Duration) -> Result<String, CommandError> {
+    let mut child = Command::new(binary)
+        .args(args)
+        // A group of its own, to kill the children holding on to the pipes too
+        .process_group(0)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|source| CommandError::Spawn { binary: binary.to_string(), source })?;
+
+    // Drain both pipes while waiting, the child blocks once a pipe is full
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        match child.try_wait().map_err(CommandError::Wait)? {
+            Some(status) => break status,
+            None if Instant::now() >= deadline => {
+                // Not reaped yet, so the group cannot belong to anyone else
+                unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+                let _ = child.wait();
+                // Done once the pipes closed with the whole group gone
+                let _ = stdout.join();
+                let _ = stderr.join();
+                return Err(CommandError::Timeout(timeout));
+            }
+            None => thread::sleep(WAIT_INTERVAL),
+        }
+    };
+
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+    if !status.success() {
+        return Err(CommandError::Failed {
+            status,
+            stderr: String::from_utf8_lossy(&stderr).trim().to_string(),
+        });
+    }
+
+    String::from_utf8(stdout).map_err(|e| CommandError::InvalidOutput(e.utf8_error()))
+}
+
+fn drain(pipe: Option<impl Read + Send + 'static>) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Spawn { binary, source } => write!(f, "failed to execute {}: {}", binary, source),
+            CommandError::Wait(e) => write!(f, "failed to wait for command: {}", e),
+            CommandError::Timeout(timeout) => write!(f, "command did not finish within {:?}", timeout),
+            CommandError::Failed { status, stderr } if stderr.is_empty() => write!(f, "command failed with {}", status),
+            CommandError::Failed { status, stderr } => write!(f, "command failed with {}: {}", status, stderr),
+            CommandError::InvalidOutput(e) => write!(f, "invalid UTF-8 in command output: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CommandError::Spawn { source, .. } | CommandError::Wait(source) => Some(source),
+            CommandError::InvalidOutput(e) => Some(e),
+            _ => None,
+        }
+    }
+}
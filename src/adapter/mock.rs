@@ -0,0 +1,31 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tempfile::TempDir;
+
+use super::solaar::SolaarAdapter;
+
+/// A fake solaar binary running a shell script, removed on drop.
+pub struct FakeSolaar {
+    _dir: TempDir,
+    path: PathBuf,
+}
+
+impl FakeSolaar {
+    pub fn new(script: &str) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("solaar");
+        fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        Self { _dir: dir, path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn adapter(&self, timeout: Duration) -> SolaarAdapter {
+        SolaarAdapter::new(self.path.to_string_lossy(), timeout)
+    }
+}
@@ -0,0 +1,9 @@
+mod command;
+pub mod solaar;
+pub mod solaar_show;
+pub mod upower;
+#[cfg(test)]
+pub mod mock;
+
+pub use solaar::SolaarAdapter;
+pub use upower::UPowerReader;
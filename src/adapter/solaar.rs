@@ -0,0 +1,235 @@
+use std::fmt;
+use std::time::Duration;
+use anyhow::Result;
+use log::debug;
+
+use crate::hardware::hid::BatteryStatus;
+use crate::hardware::source::BatterySource;
+use crate::hardware::VoltageCurve;
+use super::command::{self, CommandError};
+use super::solaar_show::SolaarShow;
+
+/// Reads the battery from the output of `solaar show`, for devices Solaar
+/// already manages.
+pub struct SolaarAdapter {
+    binary: String,
+    timeout: Duration,
+    voltage_curve: VoltageCurve,
+    bluetooth_product_ids: Vec<u16>,
+}
+
+/// Failure to get a battery reading out of Solaar.
+#[derive(Debug)]
+pub enum SolaarError {
+    Command(CommandError),
+    DeviceNotFound { vendor_id: u16, product_id: u16 },
+    NoBattery,
+}
+
+impl SolaarAdapter {
+    /// Run `binary` (e.g. `solaar` or `/usr/bin/solaar`), killing it when it
+    /// takes longer than `timeout`.
+    pub fn new(binary: impl Into<String>, timeout: Duration) -> Self {
+        Self {
+            binary: binary.into(),
+            timeout,
+            voltage_curve: VoltageCurve::default(),
+            bluetooth_product_ids: Vec::new(),
+        }
+    }
+
+    /// Curve for devices Solaar only reports a voltage for.
+    pub fn set_voltage_curve(&mut self, voltage_curve: VoltageCurve) {
+        self.voltage_curve = voltage_curve;
+    }
+
+    /// Also look for the device under one of `product_ids`, which Solaar
+    /// shows as USB id of devices connected over Bluetooth.
+    pub fn set_bluetooth_product_ids(&mut self, product_ids: Vec<u16>) {
+        self.bluetooth_product_ids = product_ids;
+    }
+
+    /// Read the battery of the device matching `vendor_id`/`product_id`.
+    ///
+    /// # Returns
+    /// * `Ok(Some(status))` - Solaar lists the device with a battery
+    /// * `Ok(None)` - Solaar does not list the device or knows no battery level
+    /// * `Err(SolaarError)` - Solaar could not be run
+    pub fn get_battery_level(&self, vendor_id: u16, product_id: u16) -> Result<Option<BatteryStatus>, SolaarError> {
+        let output = command::run(&self.binary, &["show"], self.timeout)
+            .map_err(SolaarError::Command)?;
+
+        for product_id in std::iter::once(product_id).chain(self.bluetooth_product_ids.iter().copied()) {
+            match parse_battery_from_solaar_output(&output, vendor_id, product_id, &self.voltage_curve) {
+                Ok(status) => return Ok(Some(status)),
+                Err(e @ (SolaarError::DeviceNotFound { .. } | SolaarError::NoBattery)) => {
+                    debug!("No battery reading from solaar: {}", e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl BatterySource for SolaarAdapter {
+    fn read_battery(&mut self, vendor_id: u16, product_id: u16) -> Result<Option<BatteryStatus>> {
+        Ok(self.get_battery_level(vendor_id, product_id)?)
+    }
+}
+
+impl fmt::Display for SolaarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SolaarError::Command(e) => write!(f, "solaar: {}", e),
+            SolaarError::DeviceNotFound { vendor_id, product_id } => {
+                write!(f, "device {:04x}:{:04x} not found", vendor_id, product_id)
+            }
+            SolaarError::NoBattery => write!(f, "device found but no battery information available"),
+        }
+    }
+}
+
+impl std::error::Error for SolaarError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SolaarError::Command(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Find the battery of the device matching `vendor_id`/`product_id` in solaar output
+fn parse_battery_from_solaar_output(output: &str, vendor_id: u16, product_id: u16,
+                                    voltage_curve: &VoltageCurve) -> Result<BatteryStatus, SolaarError> {
+    let show = SolaarShow::parse(output);
+    let device = show.find_device(vendor_id, product_id)
+        .ok_or(SolaarError::DeviceNotFound { vendor_id, product_id })?;
+    debug!("Found {} in solaar {} output", device.name, show.version.as_deref().unwrap_or("unknown"));
+
+    device.battery
+        .as_ref()
+        .map(|battery| battery.to_status(voltage_curve))
+        .ok_or(SolaarError::NoBattery)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapter::mock::FakeSolaar;
+    use crate::hardware::hid::ChargingStatus;
+    use std::time::Instant;
+
+    const MX_KEYS_MINI: (u16, u16) = (0x046D, 0xB369);
+
+    fn parse(output: &str) -> Result<BatteryStatus, SolaarError> {
+        parse_battery_from_solaar_output(output, MX_KEYS_MINI.0, MX_KEYS_MINI.1, &VoltageCurve::default())
+    }
+
+    #[test]
+    fn test_parse_mx_keys_mini() {
+        let sample_output = r#"solaar version 1.1.14
+
+MX Keys Mini
+     Device path  : /dev/hidraw5
+     USB id       : 046d:B369
+     Codename     : MX Keys Mini
+     Kind         : keyboard
+     Protocol     : HID++ 4.5
+     Battery: 95%, 0.
+"#;
+
+        let result = parse(sample_output).unwrap();
+        assert_eq!(result.percentage, Some(95));
+        assert_eq!(result.charging, ChargingStatus::Discharging);
+    }
+
+    #[test]
+    fn test_parse_case_insensitive() {
+        let sample_output = r#"solaar version 1.1.14
+
+MX Keys Mini
+     USB id       : 046D:b369
+     Battery: 42%, recharging.
+"#;
+
+        let result = parse(sample_output).unwrap();
+        assert_eq!(result.percentage, Some(42));
+        assert_eq!(result.charging, ChargingStatus::Charging);
+        assert!(result.external_power);
+    }
+
+    #[test]
+    fn test_device_not_found() {
+        let sample_output = r#"solaar version 1.1.14
+
+Some Other Device
+     USB id       : 046d:C094
+     Battery: 50%, 0.
+"#;
+
+        let result = parse(sample_output);
+        assert!(matches!(result, Err(SolaarError::DeviceNotFound { vendor_id: 0x046D, product_id: 0xB369 })));
+        assert!(result.unwrap_err().to_string().contains("not found"));
+    }
+
+    #[test]
+    fn test_multiple_devices() {
+        let sample_output = r#"solaar version 1.1.14
+
+PRO X Wireless
+     USB id       : 046d:C094
+     Battery: 25%, 0.
+
+MX Keys Mini
+     USB id       : 046d:B369
+     Battery: 87%, 0.
+"#;
+
+        let result = parse(sample_output).unwrap();
+        assert_eq!(result.percentage, Some(87));
+    }
+
+    #[test]
+    fn test_run_solaar_binary() {
+        let solaar = FakeSolaar::new("echo 'MX Keys Mini'; echo '     USB id       : 046d:B369'; echo '     Battery: 64%, 0.'");
+        let status = solaar.adapter(Duration::from_secs(5)).get_battery_level(0x046D, 0xB369).unwrap();
+        assert_eq!(status.and_then(|status| status.percentage), Some(64));
+
+        // Other devices are no error
+        let mut adapter = solaar.adapter(Duration::from_secs(5));
+        let status = adapter.get_battery_level(0x046D, 0xC548).unwrap();
+        assert_eq!(status, None);
+
+        // Connected over Bluetooth while the cable only charges
+        adapter.set_bluetooth_product_ids(vec![0xB369]);
+        let status = adapter.get_battery_level(0x046D, 0xC548).unwrap();
+        assert_eq!(status.and_then(|status| status.percentage), Some(64));
+    }
+
+    #[test]
+    fn test_solaar_failures() {
+        let hanging = FakeSolaar::new("exec sleep 5");
+        let started = Instant::now();
+        let result = hanging.adapter(Duration::from_millis(100)).get_battery_level(0x046D, 0xB369);
+        assert!(matches!(result, Err(SolaarError::Command(CommandError::Timeout(_)))));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // Children of the command keep its pipes open until they are killed too
+        let forking = FakeSolaar::new("sleep 5; echo 'MX Keys Mini'");
+        let started = Instant::now();
+        let result = forking.adapter(Duration::from_millis(100)).get_battery_level(0x046D, 0xB369);
+        assert!(matches!(result, Err(SolaarError::Command(CommandError::Timeout(_)))));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let failing = FakeSolaar::new("echo 'No devices found' >&2; exit 1");
+        match failing.adapter(Duration::from_secs(5)).get_battery_level(0x046D, 0xB369) {
+            Err(SolaarError::Command(CommandError::Failed { stderr, .. })) => assert_eq!(stderr, "No devices found"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let missing = SolaarAdapter::new("/nonexistent/solaar", Duration::from_secs(5));
+        assert!(matches!(missing.get_battery_level(0x046D, 0xB369), Err(SolaarError::Command(CommandError::Spawn { .. }))));
+    }
+}
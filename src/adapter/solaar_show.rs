@@ -0,0 +1,534 @@
+use crate::hardware::hid::{BatteryLevel, BatteryStatus, ChargingStatus, LOGITECH_VENDOR_ID};
+use crate::hardware::VoltageCurve;
+
+const VERSION_PREFIX: &str = "solaar version ";
+// Receivers print their fields two columns in, devices five
+const RECEIVER_FIELD_INDENT: usize = 2;
+const DEVICE_FIELD_INDENT: usize = 5;
+const DEVICE_FIELDS: [&str; 10] = [
+    "Device path", "USB id", "WPID", "Codename", "Kind", "Protocol",
+    "Serial number", "Model ID", "Unit ID", "Battery",
+];
+
+/// Everything `solaar show` prints about receivers and devices.
+///
+/// Sections are told apart by their indentation and the untranslated field
+/// labels, so receiver names, device kinds and battery states may be in any
+/// UI language.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SolaarShow {
+    pub version: Option<String>,
+    pub receivers: Vec<SolaarReceiver>,
+    // Devices connected over USB or Bluetooth without a receiver
+    pub devices: Vec<SolaarDevice>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SolaarReceiver {
+    pub name: String,
+    pub path: Option<String>,
+    pub usb_id: Option<(u16, u16)>,
+    pub serial: Option<String>,
+    pub firmware: Vec<SolaarFirmware>,
+    pub devices: Vec<SolaarDevice>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SolaarDevice {
+    // Receiver slot, `None` for devices without a receiver
+    pub number: Option<u8>,
+    pub name: String,
+    // `None` while the device is offline
+    pub path: Option<String>,
+    pub usb_id: Option<(u16, u16)>,
+    // Wireless product ID of devices paired to a receiver
+    pub wpid: Option<u16>,
+    pub codename: Option<String>,
+    // As printed, translated by some Solaar versions
+    pub kind: Option<String>,
+    // e.g. "4.5", `None` while the device is offline
+    pub protocol: Option<String>,
+    pub serial: Option<String>,
+    pub model_id: Option<String>,
+    pub unit_id: Option<String>,
+    pub firmware: Vec<SolaarFirmware>,
+    pub features: Vec<SolaarFeature>,
+    pub battery: Option<SolaarBattery>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SolaarFirmware {
+    pub kind: String,
+    pub version: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SolaarFeature {
+    pub index: u8,
+    pub name: String,
+    pub id: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SolaarBattery {
+    pub percentage: Option<u8>,
+    pub level: BatteryLevel,
+    pub voltage_mv: Option<u16>,
+    pub status: SolaarBatteryStatus,
+}
+
+/// Battery states as named in Solaar, which newer versions print as numbers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SolaarBatteryStatus {
+    Discharging,
+    Recharging,
+    AlmostFull,
+    Full,
+    SlowRecharge,
+    InvalidBattery,
+    ThermalError,
+    // Translated or unknown, as printed
+    Other(String),
+}
+
+// Where lines at deeper indentation belong to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DeviceSection {
+    // Firmware versions follow the device fields
+    Fields,
+    Features,
+    // Keys, gestures and anything else listed under a header
+    Other,
+}
+
+#[derive(Default)]
+struct Parser {
+    show: SolaarShow,
+    // Name of a top-level entry until its first field tells whether it is
+    // a receiver or a device
+    pending_name: Option<String>,
+    receiver: Option<SolaarReceiver>,
+    device: Option<SolaarDevice>,
+    section: Option<DeviceSection>,
+}
+
+impl SolaarShow {
+    /// Parse the output of `solaar show`, skipping lines it does not understand.
+    pub fn parse(output: &str) -> Self {
+        let mut parser = Parser::default();
+        for line in output.lines() {
+            parser.line(line);
+        }
+        parser.finish()
+    }
+
+    /// All devices, with and without a receiver.
+    pub fn all_devices(&self) -> impl Iterator<Item = &SolaarDevice> {
+        self.devices
+            .iter()
+            .chain(self.receivers.iter().flat_map(|receiver| receiver.devices.iter()))
+    }
+
+    /// The device with USB or wireless product ID `product_id`.
+    ///
+    /// Paired devices only print the wireless product ID, which is always
+    /// assigned by Logitech.
+    pub fn find_device(&self, vendor_id: u16, product_id: u16) -> Option<&SolaarDevice> {
+        self.all_devices().find(|device| match device.usb_id {
+            Some(usb_id) => usb_id == (vendor_id, product_id),
+            None => vendor_id == LOGITECH_VENDOR_ID && device.wpid == Some(product_id),
+        })
+    }
+}
+
+impl Parser {
+    fn line(&mut self, line: &str) {
+        let content = line.trim();
+        if content.is_empty() {
+            return;
+        }
+        let indent = line.len() - line.trim_start().len();
+
+        if indent == 0 {
+            if let Some(version) = content.strip_prefix(VERSION_PREFIX) {
+                self.show.version = Some(version.to_string());
+            } else {
+                self.finish_receiver();
+                self.pending_name = Some(content.to_string());
+            }
+            return;
+        }
+
+        if indent < DEVICE_FIELD_INDENT {
+            self.receiver_line(indent, content);
+        } else {
+            self.device_line(indent, content);
+        }
+    }
+
+    fn receiver_line(&mut self, indent: usize, content: &str) {
+        if let Some(name) = self.pending_name.take() {
+            self.receiver = Some(SolaarReceiver { name, ..Default::default() });
+        }
+
+        // "  1: MX Master 3"
+        if let Some((number, name)) = numbered(content) {
+            if self.receiver.is_some() {
+                self.finish_device();
+                self.device = Some(SolaarDevice {
+                    number: Some(number),
+                    name: name.to_string(),
+                    ..Default::default()
+                });
+                self.section = Some(DeviceSection::Fields);
+            }
+            return;
+        }
+
+        let (Some(receiver), Some((key, value))) = (self.receiver.as_mut(), field(content)) else {
+            return;
+        };
+        if indent == RECEIVER_FIELD_INDENT {
+            match key {
+                "Device path" => receiver.path = value,
+                "USB id" => receiver.usb_id = value.as_deref().and_then(usb_id),
+                "Serial" => receiver.serial = value,
+                _ => {}
+            }
+        } else if let Some(version) = value {
+            receiver.firmware.push(SolaarFirmware { kind: key.to_string(), version });
+        }
+    }
+
+    fn device_line(&mut self, indent: usize, content: &str) {
+        if let Some(name) = self.pending_name.take() {
+            self.device = Some(SolaarDevice { name, ..Default::default() });
+            self.section = Some(DeviceSection::Fields);
+        }
+        let Some(device) = self.device.as_mut() else {
+            return;
+        };
+
+        if indent > DEVICE_FIELD_INDENT {
+            match self.section {
+                Some(DeviceSection::Fields) => {
+                    // "       Bootloader: BOT 95.01.B0015"
+                    if let Some((kind, Some(version))) = field(content) {
+                        device.firmware.push(SolaarFirmware { kind: kind.to_string(), version });
+                    }
+                }
+                Some(DeviceSection::Features) => {
+                    if let Some(feature) = feature(content) {
+                        device.features.push(feature);
+                    }
+                }
+                _ => {}
+            }
+            return;
+        }
+
+        let Some((key, value)) = field(content) else {
+            return;
+        };
+        // "     Supports 28 HID++ 2.0 features:", unlike an empty "Serial number:"
+        if value.is_none() && !DEVICE_FIELDS.contains(&key) {
+            self.section = Some(if key.contains("HID++ 2.0 features") {
+                DeviceSection::Features
+            } else {
+                DeviceSection::Other
+            });
+            return;
+        }
+        if self.section == Some(DeviceSection::Features) {
+            self.section = Some(DeviceSection::Other);
+        }
+
+        match key {
+            "Device path" => device.path = value.filter(|path| path != "None"),
+            "USB id" => device.usb_id = value.as_deref().and_then(usb_id),
+            "WPID" => device.wpid = value.and_then(|wpid| u16::from_str_radix(&wpid, 16).ok()),
+            "Codename" => device.codename = value,
+            "Kind" => device.kind = value,
+            "Protocol" => {
+                device.protocol = value.and_then(|protocol| protocol.strip_prefix("HID++ ").map(str::to_string));
+            }
+            "Serial number" => device.serial = value,
+            "Model ID" => device.model_id = value,
+            "Unit ID" => device.unit_id = value,
+            "Battery" => device.battery = value.as_deref().and_then(SolaarBattery::parse),
+            _ => {}
+        }
+    }
+
+    fn finish_device(&mut self) {
+        let Some(device) = self.device.take() else {
+            return;
+        };
+        match self.receiver.as_mut() {
+            Some(receiver) if device.number.is_some() => receiver.devices.push(device),
+            _ => self.show.devices.push(device),
+        }
+        self.section = None;
+    }
+
+    fn finish_receiver(&mut self) {
+        self.finish_device();
+        if let Some(receiver) = self.receiver.take() {
+            self.show.receivers.push(receiver);
+        }
+        self.pending_name = None;
+    }
+
+    fn finish(mut self) -> SolaarShow {
+        self.finish_receiver();
+        self.show
+    }
+}
+
+impl SolaarBattery {
+    /// Parse the value of a battery line, e.g. `95%, discharging` or
+    /// `good, 0, next level 20%`.
+    fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.trim_end_matches('.').split(',');
+
+        let mut percentage = None;
+        let mut level = BatteryLevel::Unknown;
+        let mut voltage_mv = None;
+        for token in parts.next()?.split_whitespace() {
+            if let Some(value) = token.strip_suffix('%') {
+                percentage = value.parse::<u8>().ok().filter(|percentage| *percentage <= 100);
+            } else if let Some(value) = token.strip_suffix("mV") {
+                voltage_mv = value.parse().ok();
+            } else {
+                level = match token {
+                    "critical" => BatteryLevel::Critical,
+                    "low" => BatteryLevel::Low,
+                    "good" => BatteryLevel::Good,
+                    "full" => BatteryLevel::Full,
+                    _ => level,
+                };
+            }
+        }
+        // "unknown (device is offline)"
+        if percentage.is_none() && voltage_mv.is_none() && level == BatteryLevel::Unknown {
+            return None;
+        }
+        if let Some(percentage) = percentage {
+            level = BatteryLevel::from_percentage(percentage);
+        }
+
+        let status = parts.next()
+            .map(|status| SolaarBatteryStatus::parse(status.trim()))
+            .unwrap_or(SolaarBatteryStatus::Discharging);
+
+        Some(Self { percentage, level, voltage_mv, status })
+    }
+
+    /// The reading as a `BatteryStatus`, mapping voltages with `voltage_curve`.
+    pub fn to_status(&self, voltage_curve: &VoltageCurve) -> BatteryStatus {
+        let percentage = self.percentage
+            .or_else(|| self.voltage_mv.map(|voltage_mv| voltage_curve.percentage(voltage_mv)));
+        let level = match (self.level, percentage) {
+            (BatteryLevel::Unknown, Some(percentage)) => BatteryLevel::from_percentage(percentage),
+            (level, _) => level,
+        };
+        let charging = match self.status {
+            SolaarBatteryStatus::Recharging | SolaarBatteryStatus::AlmostFull => ChargingStatus::Charging,
+            SolaarBatteryStatus::SlowRecharge => ChargingStatus::SlowCharging,
+            SolaarBatteryStatus::Full => ChargingStatus::Full,
+            SolaarBatteryStatus::InvalidBattery | SolaarBatteryStatus::ThermalError => ChargingStatus::Error,
+            SolaarBatteryStatus::Discharging | SolaarBatteryStatus::Other(_) => ChargingStatus::Discharging,
+        };
+
+        BatteryStatus {
+            percentage,
+            level,
+            charging,
+            external_power: matches!(charging, ChargingStatus::Charging | ChargingStatus::SlowCharging | ChargingStatus::Full),
+            voltage_mv: self.voltage_mv,
+        }
+    }
+}
+
+impl SolaarBatteryStatus {
+    fn parse(status: &str) -> Self {
+        match status {
+            "0" | "discharging" => SolaarBatteryStatus::Discharging,
+            "1" | "recharging" => SolaarBatteryStatus::Recharging,
+            "2" | "almost full" => SolaarBatteryStatus::AlmostFull,
+            "3" | "full" => SolaarBatteryStatus::Full,
+            "4" | "slow recharge" => SolaarBatteryStatus::SlowRecharge,
+            "5" | "invalid battery" => SolaarBatteryStatus::InvalidBattery,
+            "6" | "thermal error" => SolaarBatteryStatus::ThermalError,
+            other => SolaarBatteryStatus::Other(other.to_string()),
+        }
+    }
+}
+
+/// Split `Label : value` into the label and the value, `None` when empty.
+fn field(content: &str) -> Option<(&str, Option<String>)> {
+    let (key, value) = content.split_once(':')?;
+    let value = value.trim();
+    Some((key.trim(), (!value.is_empty()).then(|| value.to_string())))
+}
+
+/// `046d:C52B` as vendor and product ID.
+fn usb_id(value: &str) -> Option<(u16, u16)> {
+    let (vendor_id, product_id) = value.split_once(':')?;
+    Some((u16::from_str_radix(vendor_id.trim(), 16).ok()?, u16::from_str_radix(product_id.trim(), 16).ok()?))
+}
+
+/// `N: rest` of numbered list entries.
+fn numbered(content: &str) -> Option<(u8, &str)> {
+    let (number, rest) = content.split_once(": ")?;
+    Some((number.parse().ok()?, rest.trim()))
+}
+
+/// `6: UNIFIED BATTERY {1004} V2`
+fn feature(content: &str) -> Option<SolaarFeature> {
+    let (index, rest) = numbered(content)?;
+    let (name, rest) = rest.split_once('{')?;
+    let (id, _) = rest.split_once('}')?;
+    Some(SolaarFeature {
+        index,
+        name: name.trim().to_string(),
+        id: u16::from_str_radix(id, 16).ok()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLUETOOTH_1_1_14: &str = include_str!("../../tests/fixtures/solaar/1.1.14-en-bluetooth.txt");
+    const UNIFYING_1_1_10: &str = include_str!("../../tests/fixtures/solaar/1.1.10-en-unifying.txt");
+    const BOLT_1_1_11_RU: &str = include_str!("../../tests/fixtures/solaar/1.1.11-ru-bolt.txt");
+    const VOLTAGE_1_0_4_DE: &str = include_str!("../../tests/fixtures/solaar/1.0.4-de-voltage.txt");
+
+    #[test]
+    fn test_parse_bluetooth_device() {
+        let show = SolaarShow::parse(BLUETOOTH_1_1_14);
+        assert_eq!(show.version.as_deref(), Some("1.1.14"));
+        assert!(show.receivers.is_empty());
+        assert_eq!(show.devices.len(), 1);
+
+        let device = &show.devices[0];
+        assert_eq!(device.number, None);
+        assert_eq!(device.name, "MX Keys Mini");
+        assert_eq!(device.path.as_deref(), Some("/dev/hidraw5"));
+        assert_eq!(device.usb_id, Some((0x046D, 0xB369)));
+        assert_eq!(device.codename.as_deref(), Some("MX Keys Mini"));
+        assert_eq!(device.kind.as_deref(), Some("keyboard"));
+        assert_eq!(device.protocol.as_deref(), Some("4.5"));
+        assert_eq!(device.serial, None);
+        assert_eq!(device.model_id.as_deref(), Some("B36900000000"));
+        assert_eq!(device.unit_id.as_deref(), Some("4E5A1C2D"));
+        assert_eq!(device.firmware, vec![
+            SolaarFirmware { kind: "Bootloader".to_string(), version: "BL1 48.00.B0009".to_string() },
+            SolaarFirmware { kind: "Firmware".to_string(), version: "RBK 73.00.B0010".to_string() },
+        ]);
+
+        // Feature details and the keys list are not features
+        assert_eq!(device.features.len(), 10);
+        assert_eq!(device.features[6], SolaarFeature { index: 6, name: "UNIFIED BATTERY".to_string(), id: 0x1004 });
+        assert_eq!(device.features[9].id, 0x1982);
+
+        // Python 3.11 prints the status enum as a number
+        assert_eq!(device.battery, Some(SolaarBattery {
+            percentage: Some(95),
+            level: BatteryLevel::Full,
+            voltage_mv: None,
+            status: SolaarBatteryStatus::Discharging,
+        }));
+    }
+
+    #[test]
+    fn test_parse_unifying_receiver() {
+        let show = SolaarShow::parse(UNIFYING_1_1_10);
+        assert!(show.devices.is_empty());
+        assert_eq!(show.receivers.len(), 1);
+
+        let receiver = &show.receivers[0];
+        assert_eq!(receiver.name, "Unifying Receiver");
+        assert_eq!(receiver.path.as_deref(), Some("/dev/hidraw0"));
+        assert_eq!(receiver.usb_id, Some((0x046D, 0xC52B)));
+        assert_eq!(receiver.serial.as_deref(), Some("5F2A0B1C"));
+        assert_eq!(receiver.firmware.len(), 3);
+        assert_eq!(receiver.firmware[0], SolaarFirmware { kind: "Firmware".to_string(), version: "12.11.B0032".to_string() });
+        assert_eq!(receiver.devices.len(), 2);
+
+        let mouse = &receiver.devices[0];
+        assert_eq!((mouse.number, mouse.name.as_str()), (Some(1), "MX Master 3"));
+        assert_eq!(mouse.wpid, Some(0x4082));
+        assert_eq!(mouse.usb_id, None);
+        assert_eq!(mouse.serial.as_deref(), Some("7C1D2E3F"));
+        assert_eq!(mouse.firmware.len(), 2);
+        assert_eq!(mouse.features.len(), 6);
+        assert_eq!(mouse.battery.as_ref().map(|battery| (battery.percentage, &battery.status)),
+                   Some((Some(90), &SolaarBatteryStatus::Discharging)));
+
+        let keyboard = &receiver.devices[1];
+        assert_eq!((keyboard.number, keyboard.name.as_str()), (Some(2), "Wireless Keyboard K270"));
+        assert_eq!(keyboard.path, None);
+        assert_eq!(keyboard.protocol, None);
+        assert_eq!(keyboard.battery, None);
+    }
+
+    #[test]
+    fn test_parse_translated_output() {
+        let show = SolaarShow::parse(BOLT_1_1_11_RU);
+        assert_eq!(show.receivers.len(), 1);
+        assert_eq!(show.receivers[0].name, "Приёмник Bolt");
+        assert_eq!(show.receivers[0].usb_id, Some((0x046D, 0xC548)));
+
+        let device = &show.receivers[0].devices[0];
+        assert_eq!(device.name, "MX Keys Mini");
+        assert_eq!(device.kind.as_deref(), Some("клавиатура"));
+        assert_eq!(device.firmware.len(), 2);
+        assert_eq!(device.features.len(), 3);
+
+        let battery = device.battery.as_ref().unwrap();
+        assert_eq!(battery.percentage, Some(60));
+        assert_eq!(battery.status, SolaarBatteryStatus::Other("заряжается".to_string()));
+    }
+
+    #[test]
+    fn test_parse_voltage_and_level_batteries() {
+        let show = SolaarShow::parse(VOLTAGE_1_0_4_DE);
+        assert_eq!(show.version.as_deref(), Some("1.0.4"));
+        let devices = &show.receivers[0].devices;
+        assert_eq!(devices.len(), 2);
+
+        let mouse = devices[0].battery.as_ref().unwrap();
+        assert_eq!((mouse.percentage, mouse.voltage_mv), (None, Some(3850)));
+        assert_eq!(mouse.status, SolaarBatteryStatus::Recharging);
+        let status = mouse.to_status(&VoltageCurve::default());
+        assert!(status.percentage.is_some());
+        assert_eq!(status.charging, ChargingStatus::Charging);
+        assert_eq!(status.voltage_mv, Some(3850));
+
+        let keyboard = devices[1].battery.as_ref().unwrap();
+        assert_eq!((keyboard.percentage, keyboard.level), (None, BatteryLevel::Good));
+        assert_eq!(keyboard.to_status(&VoltageCurve::default()).percentage_or_estimate(), Some(50));
+    }
+
+    #[test]
+    fn test_find_device() {
+        let show = SolaarShow::parse(BLUETOOTH_1_1_14);
+        assert!(show.find_device(0x046D, 0xB369).is_some());
+        assert!(show.find_device(0x046D, 0xC548).is_none());
+
+        // Paired devices by wireless product ID
+        let show = SolaarShow::parse(UNIFYING_1_1_10);
+        assert_eq!(show.find_device(0x046D, 0x4082).map(|device| device.name.as_str()), Some("MX Master 3"));
+        assert!(show.find_device(0x1234, 0x4082).is_none());
+        // Receivers are not devices
+        assert!(show.find_device(0x046D, 0xC52B).is_none());
+    }
+
+    #[test]
+    fn test_parse_garbage() {
+        assert_eq!(SolaarShow::parse(""), SolaarShow::default());
+        assert_eq!(SolaarShow::parse("No Logitech receiver found\n"), SolaarShow::default());
+    }
+}
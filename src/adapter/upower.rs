@@ -0,0 +1,468 @@
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::thread;
+use std::time::Duration;
+use anyhow::{bail, Context, Result};
+use log::debug;
+use zbus::blocking::Connection;
+use zbus::zvariant::{OwnedObjectPath, OwnedValue};
+
+use crate::hardware::hid::{BatteryLevel, BatteryStatus, ChargingStatus};
+use crate::hardware::source::BatterySource;
+
+const UPOWER_SERVICE: &str = "org.freedesktop.UPower";
+const UPOWER_PATH: &str = "/org/freedesktop/UPower";
+const DEVICE_INTERFACE: &str = "org.freedesktop.UPower.Device";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+
+// UPower Device State values
+const STATE_CHARGING: u32 = 1;
+const STATE_FULLY_CHARGED: u32 = 4;
+const STATE_PENDING_CHARGE: u32 = 5;
+
+// UPower Device BatteryLevel values, None (1) for devices reporting percentages
+const LEVEL_LOW: u32 = 3;
+const LEVEL_CRITICAL: u32 = 4;
+const LEVEL_NORMAL: u32 = 6;
+const LEVEL_HIGH: u32 = 7;
+const LEVEL_FULL: u32 = 8;
+
+/// Reads the battery UPower tracks for the device over the system D-Bus,
+/// matched by model name and serial number since UPower does not expose USB
+/// IDs. Needs no access to the device nodes.
+pub struct UPowerReader {
+    model: Option<String>,
+    serial: Option<String>,
+    timeout: Duration,
+    // Bus to connect to instead of the system bus
+    address: Option<String>,
+    connection: Option<Connection>,
+    // Query that timed out and is still waiting for D-Bus or UPower
+    pending: Option<Receiver<QueryAnswer>>,
+}
+
+/// Devices UPower tracks, with the connection they were queried over.
+type QueryAnswer = Result<(Connection, Vec<UPowerDevice>)>;
+
+/// Properties of an `org.freedesktop.UPower.Device`.
+#[derive(Debug, Default, PartialEq)]
+struct UPowerDevice {
+    object_path: String,
+    native_path: Option<String>,
+    model: Option<String>,
+    serial: Option<String>,
+    state: u32,
+    percentage: Option<f64>,
+    battery_level: u32,
+}
+
+impl UPowerReader {
+    /// Match devices whose model contains `model` (case-insensitive) and whose
+    /// serial number is `serial`, each criterion only when given. UPower has
+    /// `timeout` to answer.
+    pub fn new(model: Option<String>, serial: Option<String>, timeout: Duration) -> Self {
+        Self { model, serial, timeout, address: None, connection: None, pending: None }
+    }
+
+    /// Like `new`, but query the bus at `address`, e.g. `unix:path=/run/dbus/test`.
+    #[cfg(test)]
+    pub fn with_address(model: Option<String>, serial: Option<String>, timeout: Duration, address: impl Into<String>) -> Self {
+        Self { address: Some(address.into()), ..Self::new(model, serial, timeout) }
+    }
+
+    /// Read the battery of the matching device.
+    ///
+    /// # Returns
+    /// * `Ok(Some(status))` - UPower tracks a matching device
+    /// * `Ok(None)` - No device matches, or no criterion is configured
+    pub fn get_battery_level(&mut self) -> Result<Option<BatteryStatus>> {
+        if self.model.is_none() && self.serial.is_none() {
+            debug!("Neither model nor serial number configured to find the device in UPower");
+            return Ok(None);
+        }
+
+        let result = self.find_device();
+        if result.is_err() {
+            // Reconnect next time, e.g. after the bus restarted
+            self.connection = None;
+        }
+
+        Ok(result?.and_then(|device| {
+            debug!("Found {} ({:?}) in UPower", device.object_path, device.native_path);
+            device.battery_status()
+        }))
+    }
+
+    fn find_device(&mut self) -> Result<Option<UPowerDevice>> {
+        if let Some(pending) = &self.pending {
+            // At most one query hangs at a time
+            if let Err(TryRecvError::Empty) = pending.try_recv() {
+                bail!("UPower has not answered the previous query yet");
+            }
+            self.pending = None;
+        }
+
+        // Connecting and blocking calls have no timeout, so they run on a
+        // thread of their own. Cheap, the connection is reference counted.
+        let connection = self.connection.clone();
+        let address = self.address.clone();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let answer = match connection {
+                Some(connection) => Ok(connection),
+                None => connect(address.as_deref()),
+            }
+            .and_then(|connection| {
+                let devices = query_devices(&connection)?;
+                Ok((connection, devices))
+            });
+            let _ = sender.send(answer);
+        });
+
+        match receiver.recv_timeout(self.timeout) {
+            Ok(answer) => {
+                let (connection, devices) = answer?;
+                self.connection = Some(connection);
+                Ok(devices.into_iter().find(|device| self.matches(device)))
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                self.pending = Some(receiver);
+                bail!("UPower did not answer within {:?}", self.timeout)
+            }
+            Err(RecvTimeoutError::Disconnected) => bail!("UPower query ended without an answer"),
+        }
+    }
+
+    fn matches(&self, device: &UPowerDevice) -> bool {
+        let model_matches = match (&self.model, &device.model) {
+            (Some(wanted), Some(model)) => model.to_lowercase().contains(&wanted.to_lowercase()),
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+        let serial_matches = match (&self.serial, &device.serial) {
+            (Some(wanted), Some(serial)) => wanted.eq_ignore_ascii_case(serial),
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+        model_matches && serial_matches
+    }
+}
+
+impl BatterySource for UPowerReader {
+    fn read_battery(&mut self, _vendor_id: u16, _product_id: u16) -> Result<Option<BatteryStatus>> {
+        self.get_battery_level()
+    }
+}
+
+impl UPowerDevice {
+    fn from_properties(object_path: &str, properties: &HashMap<String, OwnedValue>) -> Self {
+        Self {
+            object_path: object_path.to_string(),
+            native_path: property(properties, "NativePath"),
+            model: property(properties, "Model"),
+            serial: property(properties, "Serial"),
+            state: property(properties, "State").unwrap_or_default(),
+            percentage: property(properties, "Percentage"),
+            battery_level: property(properties, "BatteryLevel").unwrap_or_default(),
+        }
+    }
+
+    fn battery_status(&self) -> Option<BatteryStatus> {
+        let level = match self.battery_level {
+            LEVEL_CRITICAL => Some(BatteryLevel::Critical),
+            LEVEL_LOW => Some(BatteryLevel::Low),
+            LEVEL_NORMAL | LEVEL_HIGH => Some(BatteryLevel::Good),
+            LEVEL_FULL => Some(BatteryLevel::Full),
+            _ => None,
+        };
+        // The percentage of level-only devices is made up from the level
+        let percentage = match level {
+            Some(_) => None,
+            None => Some(self.percentage?.round().clamp(0.0, 100.0) as u8),
+        };
+
+        let charging = match self.state {
+            STATE_CHARGING => ChargingStatus::Charging,
+            STATE_FULLY_CHARGED => ChargingStatus::Full,
+            _ => ChargingStatus::Discharging,
+        };
+
+        Some(BatteryStatus {
+            percentage,
+            level: level.unwrap_or_else(|| percentage.map_or(BatteryLevel::Unknown, BatteryLevel::from_percentage)),
+            charging,
+            external_power: matches!(self.state, STATE_CHARGING | STATE_FULLY_CHARGED | STATE_PENDING_CHARGE),
+            voltage_mv: None,
+        })
+    }
+}
+
+/// Connect to the bus at `address`, the system bus if none.
+fn connect(address: Option<&str>) -> Result<Connection> {
+    let connection = match address {
+        Some(address) => zbus::blocking::connection::Builder::address(address)?.build(),
+        None => Connection::system(),
+    };
+    connection.context("Failed to connect to D-Bus")
+}
+
+/// Properties of every device UPower tracks.
+fn query_devices(connection: &Connection) -> Result<Vec<UPowerDevice>> {
+    let paths: Vec<OwnedObjectPath> = connection
+        .call_method(Some(UPOWER_SERVICE), UPOWER_PATH, Some(UPOWER_SERVICE), "EnumerateDevices", &())
+        .context("Failed to enumerate UPower devices")?
+        .body()
+        .deserialize()?;
+
+    paths.iter()
+        .map(|path| {
+            let properties: HashMap<String, OwnedValue> = connection
+                .call_method(Some(UPOWER_SERVICE), path.as_str(), Some(PROPERTIES_INTERFACE), "GetAll", &(DEVICE_INTERFACE,))
+                .with_context(|| format!("Failed to read properties of {}", path.as_str()))?
+                .body()
+                .deserialize()?;
+            Ok(UPowerDevice::from_properties(path.as_str(), &properties))
+        })
+        .collect()
+}
+
+fn property<T: TryFrom<OwnedValue>>(properties: &HashMap<String, OwnedValue>, name: &str) -> Option<T> {
+    let value = properties.get(name)?.try_clone().ok()?;
+    T::try_from(value).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use std::time::Instant;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    const BUS_CONFIG: &str = r#"<!DOCTYPE busconfig PUBLIC "-//freedesktop//DTD D-Bus Bus Configuration 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/busconfig.dtd">
+<busconfig>
+  <type>session</type>
+  <listen>unix:tmpdir=/tmp</listen>
+  <auth>EXTERNAL</auth>
+  <policy context="default">
+    <allow send_destination="*" eavesdrop="true"/>
+    <allow eavesdrop="true"/>
+    <allow own="*"/>
+  </policy>
+</busconfig>
+"#;
+
+    /// A private `dbus-daemon` for the duration of a test, none when it is not
+    /// installed.
+    struct TestBus {
+        daemon: Child,
+        _config_dir: tempfile::TempDir,
+        address: String,
+    }
+
+    impl TestBus {
+        fn start() -> Option<Self> {
+            let config_dir = tempfile::tempdir().unwrap();
+            let config = config_dir.path().join("bus.conf");
+            std::fs::write(&config, BUS_CONFIG).unwrap();
+
+            let mut daemon = match Command::new("dbus-daemon")
+                .arg(format!("--config-file={}", config.display()))
+                .args(["--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn() {
+                Ok(daemon) => daemon,
+                Err(e) => {
+                    eprintln!("Skipping test, dbus-daemon not available: {}", e);
+                    return None;
+                }
+            };
+
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap()).read_line(&mut address).unwrap();
+            Some(Self { daemon, _config_dir: config_dir, address: address.trim().to_string() })
+        }
+    }
+
+    impl Drop for TestBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    struct StubUPower {
+        devices: Vec<OwnedObjectPath>,
+        delay: Duration,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.UPower")]
+    impl StubUPower {
+        fn enumerate_devices(&self) -> Vec<OwnedObjectPath> {
+            thread::sleep(self.delay);
+            self.devices.clone()
+        }
+    }
+
+    struct StubDevice {
+        native_path: String,
+        model: String,
+        serial: String,
+        state: u32,
+        percentage: f64,
+        battery_level: u32,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.UPower.Device")]
+    impl StubDevice {
+        #[zbus(property)]
+        fn native_path(&self) -> String {
+            self.native_path.clone()
+        }
+
+        #[zbus(property)]
+        fn model(&self) -> String {
+            self.model.clone()
+        }
+
+        #[zbus(property)]
+        fn serial(&self) -> String {
+            self.serial.clone()
+        }
+
+        #[zbus(property)]
+        fn state(&self) -> u32 {
+            self.state
+        }
+
+        #[zbus(property)]
+        fn percentage(&self) -> f64 {
+            self.percentage
+        }
+
+        #[zbus(property)]
+        fn battery_level(&self) -> u32 {
+            self.battery_level
+        }
+    }
+
+    fn stub_device(native_path: &str, model: &str, serial: &str, state: u32, percentage: f64, battery_level: u32) -> StubDevice {
+        StubDevice {
+            native_path: native_path.to_string(),
+            model: model.to_string(),
+            serial: serial.to_string(),
+            state,
+            percentage,
+            battery_level,
+        }
+    }
+
+    fn serve_stub_upower(address: &str) -> Connection {
+        let laptop = "/org/freedesktop/UPower/devices/battery_BAT0";
+        let keyboard = "/org/freedesktop/UPower/devices/keyboard_hidpp_battery_1";
+        let mouse = "/org/freedesktop/UPower/devices/mouse_hidpp_battery_2";
+        let devices = [laptop, keyboard, mouse]
+            .iter()
+            .map(|path| OwnedObjectPath::try_from(*path).unwrap())
+            .collect();
+
+        zbus::blocking::connection::Builder::address(address).unwrap()
+            .name(UPOWER_SERVICE).unwrap()
+            .serve_at(UPOWER_PATH, StubUPower { devices, delay: Duration::ZERO }).unwrap()
+            .serve_at(laptop, stub_device("BAT0", "5B10W13930", "1234", 2, 64.0, 1)).unwrap()
+            .serve_at(keyboard, stub_device("hidpp_battery_1", "MX Keys Mini", "1a2b3c4d", STATE_CHARGING, 72.0, 1)).unwrap()
+            .serve_at(mouse, stub_device("hidpp_battery_2", "M720 Triathlon", "6a7b8c9d", 2, 10.0, LEVEL_LOW)).unwrap()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_read_battery_from_stub_upower() {
+        let Some(bus) = TestBus::start() else {
+            return;
+        };
+        let _upower = serve_stub_upower(&bus.address);
+
+        let mut reader = UPowerReader::with_address(Some("keys mini".to_string()), None, TIMEOUT, bus.address.clone());
+        let status = reader.get_battery_level().unwrap().unwrap();
+        assert_eq!(status.percentage, Some(72));
+        assert_eq!(status.charging, ChargingStatus::Charging);
+        assert!(status.external_power);
+
+        let mut reader = UPowerReader::with_address(Some("MX Keys".to_string()), Some("FFFFFFFF".to_string()), TIMEOUT, bus.address.clone());
+        assert_eq!(reader.get_battery_level().unwrap(), None);
+
+        // Level-only device, matched by serial number alone
+        let mut reader = UPowerReader::with_address(None, Some("6A7B8C9D".to_string()), TIMEOUT, bus.address.clone());
+        let status = reader.get_battery_level().unwrap().unwrap();
+        assert_eq!(status.percentage, None);
+        assert_eq!(status.level, BatteryLevel::Low);
+        assert_eq!(status.percentage_or_estimate(), Some(20));
+    }
+
+    #[test]
+    fn test_upower_not_running() {
+        let Some(bus) = TestBus::start() else {
+            return;
+        };
+
+        let mut reader = UPowerReader::with_address(Some("MX Keys".to_string()), None, TIMEOUT, bus.address.clone());
+        assert!(reader.get_battery_level().is_err());
+    }
+
+    #[test]
+    fn test_upower_not_answering() {
+        let Some(bus) = TestBus::start() else {
+            return;
+        };
+        let _upower = zbus::blocking::connection::Builder::address(bus.address.as_str()).unwrap()
+            .name(UPOWER_SERVICE).unwrap()
+            .serve_at(UPOWER_PATH, StubUPower { devices: Vec::new(), delay: Duration::from_secs(2) }).unwrap()
+            .build()
+            .unwrap();
+
+        let mut reader = UPowerReader::with_address(Some("MX Keys".to_string()), None, Duration::from_millis(200), bus.address.clone());
+        let started = Instant::now();
+        let err = reader.get_battery_level().unwrap_err();
+        assert!(err.to_string().contains("did not answer"));
+        // Not queried again while the first query hangs
+        let err = reader.get_battery_level().unwrap_err();
+        assert!(err.to_string().contains("previous query"));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_bus_not_answering() {
+        // Accepts connections but never authenticates them
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("bus");
+        let _listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+
+        let address = format!("unix:path={}", socket.display());
+        let mut reader = UPowerReader::with_address(Some("MX Keys".to_string()), None, Duration::from_millis(200), address);
+        let started = Instant::now();
+        let err = reader.get_battery_level().unwrap_err();
+        assert!(err.to_string().contains("did not answer"));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_nothing_configured() {
+        assert_eq!(UPowerReader::new(None, None, TIMEOUT).get_battery_level().unwrap(), None);
+    }
+
+    #[test]
+    fn test_battery_status_from_properties() {
+        let device = UPowerDevice { state: STATE_PENDING_CHARGE, percentage: Some(79.6), battery_level: 1, ..Default::default() };
+        let status = device.battery_status().unwrap();
+        assert_eq!((status.percentage, status.level), (Some(80), BatteryLevel::Good));
+        assert_eq!(status.charging, ChargingStatus::Discharging);
+        assert!(status.external_power);
+
+        let device = UPowerDevice { state: STATE_FULLY_CHARGED, percentage: None, battery_level: 1, ..Default::default() };
+        assert_eq!(device.battery_status(), None);
+    }
+}
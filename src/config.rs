@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
 use std::fs;
+use std::path::PathBuf;
 use anyhow::{Context, Result};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -7,6 +8,13 @@ pub struct Config {
     pub device: DeviceConfig,
     pub thresholds: ThresholdConfig,
     pub logging: LoggingConfig,
+    #[serde(default)]
+    pub solaar: SolaarConfig,
+    #[serde(default)]
+    pub upower: UPowerConfig,
+    // Remembers the charging state across restarts
+    #[serde(default = "default_state_file")]
+    pub state_file: PathBuf,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -14,6 +22,94 @@ pub struct DeviceConfig {
     pub vendor_id: u16,
     pub product_id: u16,
     pub name: String,
+    // Voltage-to-percentage curve for BatteryVoltage (0x1001) devices,
+    // defaults to a single Li-ion cell
+    #[serde(default)]
+    pub voltage_curve: Option<Vec<VoltagePoint>>,
+    // Narrows down which HID interface to talk to, all criteria must match
+    #[serde(default)]
+    pub hid: HidSelector,
+    #[serde(default)]
+    pub hid_backend: HidBackend,
+    // Product IDs the device uses when connected over Bluetooth, where the
+    // USB product ID above does not apply
+    #[serde(default)]
+    pub bluetooth_product_ids: Vec<u16>,
+    // Where to read the battery from, tried in order until one has a reading
+    #[serde(default = "default_battery_sources")]
+    pub battery_sources: Vec<BatterySourceKind>,
+    // How to stop the device charging, tried in order until one works
+    #[serde(default = "default_charging_strategies")]
+    pub charging_strategies: Vec<ChargingStrategy>,
+    // Time to wait after changing charging before checking it took effect
+    #[serde(default = "default_settle_delay_ms")]
+    pub settle_delay_ms: u64,
+    // Keep a port off for up to port_off_hold_secs while the battery cannot
+    // be read, which it often cannot while the port is off. Off by default,
+    // charging is enabled again as soon as the battery cannot be read
+    #[serde(default)]
+    pub hold_port_off: bool,
+    #[serde(default = "default_port_off_hold_secs")]
+    pub port_off_hold_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatterySourceKind {
+    // Our own HID++ requests
+    Hidpp,
+    // /sys/class/power_supply/hidpp_battery_* of the hid-logitech-hidpp driver
+    PowerSupply,
+    // Output of `solaar show`
+    Solaar,
+    // Devices tracked by the UPower daemon
+    Upower,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChargingStrategy {
+    // Let runtime PM suspend the device right away via power/control
+    RuntimeSuspend,
+    // Write 0 to the device's authorized attribute
+    Deauthorize,
+    // Unbind the device from its driver
+    Unbind,
+    // The kernel's <hub>-port<N>/disable attribute
+    PortDisable,
+    // ClearPortFeature(PORT_POWER) sent to the hub through usbfs
+    HubPowerOff,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HidBackend {
+    #[default]
+    Hidapi,
+    // Talks to /dev/hidraw* directly
+    Hidraw,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HidSelector {
+    // Case-insensitive substring of the HID product string
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub serial_number: Option<String>,
+    // hidraw node, e.g. /dev/hidraw3
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub usage_page: Option<u16>,
+    #[serde(default)]
+    pub interface_number: Option<i32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VoltagePoint {
+    pub millivolts: u16,
+    pub percentage: u8,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -28,6 +124,30 @@ pub struct LoggingConfig {
     pub use_journal: bool,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SolaarConfig {
+    // Name looked up in PATH, or absolute path
+    #[serde(default = "default_solaar_binary")]
+    pub binary: String,
+    // solaar is killed when it takes longer
+    #[serde(default = "default_solaar_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UPowerConfig {
+    // Case-insensitive substring of the model UPower reports, defaults to
+    // the name of the hid selector
+    #[serde(default)]
+    pub model: Option<String>,
+    // Defaults to the serial number of the hid selector
+    #[serde(default)]
+    pub serial: Option<String>,
+    // UPower counts as unavailable when it takes longer to answer
+    #[serde(default = "default_upower_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
@@ -35,6 +155,15 @@ impl Default for Config {
                 vendor_id: 0x05e3, // Logitech
                 product_id: 0x0608, // MX Mini
                 name: "Logitech MX Mini".to_string(),
+                voltage_curve: None,
+                hid: HidSelector::default(),
+                hid_backend: HidBackend::default(),
+                bluetooth_product_ids: Vec::new(),
+                battery_sources: default_battery_sources(),
+                charging_strategies: default_charging_strategies(),
+                settle_delay_ms: default_settle_delay_ms(),
+                hold_port_off: false,
+                port_off_hold_secs: default_port_off_hold_secs(),
             },
             thresholds: ThresholdConfig {
                 high_threshold: 80,
@@ -44,6 +173,91 @@ impl Default for Config {
                 level: "info".to_string(),
                 use_journal: true,
             },
+            solaar: SolaarConfig::default(),
+            upower: UPowerConfig::default(),
+            state_file: default_state_file(),
+        }
+    }
+}
+
+fn default_battery_sources() -> Vec<BatterySourceKind> {
+    vec![BatterySourceKind::Hidpp, BatterySourceKind::PowerSupply]
+}
+
+fn default_charging_strategies() -> Vec<ChargingStrategy> {
+    vec![ChargingStrategy::PortDisable, ChargingStrategy::HubPowerOff]
+}
+
+fn default_settle_delay_ms() -> u64 {
+    5000
+}
+
+// One poll interval
+fn default_port_off_hold_secs() -> u64 {
+    10
+}
+
+fn default_state_file() -> PathBuf {
+    PathBuf::from("/var/lib/mx-mini-battery-manager/state.json")
+}
+
+fn default_solaar_binary() -> String {
+    "solaar".to_string()
+}
+
+fn default_solaar_timeout_secs() -> u64 {
+    10
+}
+
+impl Default for SolaarConfig {
+    fn default() -> Self {
+        Self {
+            binary: default_solaar_binary(),
+            timeout_secs: default_solaar_timeout_secs(),
+        }
+    }
+}
+
+fn default_upower_timeout_secs() -> u64 {
+    5
+}
+
+impl Default for UPowerConfig {
+    fn default() -> Self {
+        Self {
+            model: None,
+            serial: None,
+            timeout_secs: default_upower_timeout_secs(),
+        }
+    }
+}
+
+impl std::fmt::Display for BatterySourceKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BatterySourceKind::Hidpp => write!(f, "hidpp"),
+            BatterySourceKind::PowerSupply => write!(f, "power_supply"),
+            BatterySourceKind::Solaar => write!(f, "solaar"),
+            BatterySourceKind::Upower => write!(f, "upower"),
+        }
+    }
+}
+
+impl ChargingStrategy {
+    /// Whether the strategy cuts the power of the hub port.
+    pub fn switches_port_power(&self) -> bool {
+        matches!(self, ChargingStrategy::PortDisable | ChargingStrategy::HubPowerOff)
+    }
+}
+
+impl std::fmt::Display for ChargingStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChargingStrategy::RuntimeSuspend => write!(f, "runtime_suspend"),
+            ChargingStrategy::Deauthorize => write!(f, "deauthorize"),
+            ChargingStrategy::Unbind => write!(f, "unbind"),
+            ChargingStrategy::PortDisable => write!(f, "port_disable"),
+            ChargingStrategy::HubPowerOff => write!(f, "hub_power_off"),
         }
     }
 }
@@ -1,2 +1,5 @@
+pub mod policy;
+pub mod reading;
 mod service;
-pub use service::BatteryManager;
+pub mod state;
+pub use service::{restore, BatteryManager};
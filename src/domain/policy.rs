@@ -0,0 +1,93 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::config::ThresholdConfig;
+use super::service::PowerEvent;
+
+/// Whether the device is currently allowed to charge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChargingState {
+    Enabled,
+    Disabled,
+}
+
+/// Hysteresis between the two thresholds: charging stops at or above the
+/// high threshold and only resumes at or below the low one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChargingPolicy {
+    high_threshold: u8,
+    low_threshold: u8,
+}
+
+impl ChargingPolicy {
+    pub fn new(thresholds: &ThresholdConfig) -> Result<Self> {
+        if thresholds.low_threshold >= thresholds.high_threshold {
+            bail!("low_threshold ({}%) must be below high_threshold ({}%)",
+                  thresholds.low_threshold, thresholds.high_threshold);
+        }
+
+        Ok(Self {
+            high_threshold: thresholds.high_threshold,
+            low_threshold: thresholds.low_threshold,
+        })
+    }
+
+    /// Event to move on from `state` at `percentage`.
+    pub fn next_event(&self, state: ChargingState, percentage: u8) -> PowerEvent {
+        match state {
+            ChargingState::Enabled if percentage >= self.high_threshold => PowerEvent::ChargingDisabling(percentage),
+            ChargingState::Disabled if percentage <= self.low_threshold => PowerEvent::ChargingEnabling(percentage),
+            _ => PowerEvent::NoChange(percentage),
+        }
+    }
+}
+
+impl ChargingState {
+    pub fn from_enabled(enabled: bool) -> Self {
+        if enabled {
+            ChargingState::Enabled
+        } else {
+            ChargingState::Disabled
+        }
+    }
+}
+
+impl std::fmt::Display for ChargingState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChargingState::Enabled => write!(f, "enabled"),
+            ChargingState::Disabled => write!(f, "disabled"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> ChargingPolicy {
+        ChargingPolicy::new(&ThresholdConfig { high_threshold: 80, low_threshold: 20 }).unwrap()
+    }
+
+    #[test]
+    fn test_transitions() {
+        let policy = policy();
+
+        assert_eq!(policy.next_event(ChargingState::Enabled, 79), PowerEvent::NoChange(79));
+        assert_eq!(policy.next_event(ChargingState::Enabled, 80), PowerEvent::ChargingDisabling(80));
+        assert_eq!(policy.next_event(ChargingState::Enabled, 100), PowerEvent::ChargingDisabling(100));
+        assert_eq!(policy.next_event(ChargingState::Enabled, 5), PowerEvent::NoChange(5));
+
+        assert_eq!(policy.next_event(ChargingState::Disabled, 80), PowerEvent::NoChange(80));
+        assert_eq!(policy.next_event(ChargingState::Disabled, 21), PowerEvent::NoChange(21));
+        assert_eq!(policy.next_event(ChargingState::Disabled, 20), PowerEvent::ChargingEnabling(20));
+        assert_eq!(policy.next_event(ChargingState::Disabled, 0), PowerEvent::ChargingEnabling(0));
+    }
+
+    #[test]
+    fn test_invalid_thresholds() {
+        assert!(ChargingPolicy::new(&ThresholdConfig { high_threshold: 50, low_threshold: 50 }).is_err());
+        assert!(ChargingPolicy::new(&ThresholdConfig { high_threshold: 20, low_threshold: 80 }).is_err());
+    }
+}
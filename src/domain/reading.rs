@@ -0,0 +1,154 @@
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::config::BatterySourceKind;
+use crate::hardware::hid::{BatteryLevel, BatteryStatus, ChargingStatus};
+
+/// A battery reading as the charging decisions see it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatteryReading {
+    // Only known when reported by the device or derived from its voltage
+    pub percentage: Option<u8>,
+    pub level: BatteryLevel,
+    pub charging: ChargingStatus,
+    pub external_power: bool,
+    pub voltage_mv: Option<u16>,
+    pub source: ReadingSource,
+    // Wall clock time for display, the age comes from `taken_at`
+    pub timestamp: SystemTime,
+    pub taken_at: Instant,
+    pub confidence: Confidence,
+}
+
+/// Where a reading came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadingSource {
+    /// Polled from one of the configured battery sources
+    Poll(BatterySourceKind),
+    /// Pushed by the device as HID++ notification
+    Notification,
+}
+
+/// How far the percentage of a reading can be trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Confidence {
+    /// Neither percentage nor level known
+    Unknown,
+    /// Rough percentage from the level bucket
+    Approximate,
+    /// Percentage mapped from the voltage curve
+    Estimated,
+    /// State of charge reported by the device
+    Exact,
+}
+
+impl BatteryReading {
+    /// Reading of `status` taken just now from `source`.
+    pub fn new(status: BatteryStatus, source: ReadingSource) -> Self {
+        let confidence = match (status.percentage, status.voltage_mv) {
+            (Some(_), None) => Confidence::Exact,
+            (Some(_), Some(_)) => Confidence::Estimated,
+            (None, _) if status.percentage_or_estimate().is_some() => Confidence::Approximate,
+            (None, _) => Confidence::Unknown,
+        };
+
+        Self {
+            percentage: status.percentage,
+            level: status.level,
+            charging: status.charging,
+            external_power: status.external_power,
+            voltage_mv: status.voltage_mv,
+            source,
+            timestamp: SystemTime::now(),
+            taken_at: Instant::now(),
+            confidence,
+        }
+    }
+
+    /// Exact or voltage based percentage when known, otherwise the level bucket estimate.
+    pub fn percentage_or_estimate(&self) -> Option<u8> {
+        self.percentage.or_else(|| self.level.approximate_percentage())
+    }
+
+    /// Whether the reading was taken more than `max_age` ago, or when the wall
+    /// clock was set back past it, which leaves its time in doubt.
+    pub fn is_stale(&self, max_age: Duration) -> bool {
+        self.taken_at.elapsed() > max_age || self.timestamp.elapsed().is_err()
+    }
+}
+
+impl fmt::Display for ReadingSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadingSource::Poll(kind) => write!(f, "{}", kind),
+            ReadingSource::Notification => write!(f, "notification"),
+        }
+    }
+}
+
+impl fmt::Display for Confidence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Confidence::Unknown => write!(f, "unknown"),
+            Confidence::Approximate => write!(f, "approximate"),
+            Confidence::Estimated => write!(f, "estimated"),
+            Confidence::Exact => write!(f, "exact"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(percentage: Option<u8>, level: BatteryLevel, voltage_mv: Option<u16>) -> BatteryStatus {
+        BatteryStatus {
+            percentage,
+            level,
+            charging: ChargingStatus::Discharging,
+            external_power: false,
+            voltage_mv,
+        }
+    }
+
+    #[test]
+    fn test_confidence_from_status() {
+        let source = ReadingSource::Poll(BatterySourceKind::Hidpp);
+
+        let reading = BatteryReading::new(status(Some(64), BatteryLevel::Good, None), source);
+        assert_eq!(reading.confidence, Confidence::Exact);
+        assert_eq!(reading.percentage_or_estimate(), Some(64));
+
+        let reading = BatteryReading::new(status(Some(70), BatteryLevel::Good, Some(3900)), source);
+        assert_eq!(reading.confidence, Confidence::Estimated);
+        assert_eq!(reading.voltage_mv, Some(3900));
+
+        let reading = BatteryReading::new(status(None, BatteryLevel::Low, None), source);
+        assert_eq!(reading.confidence, Confidence::Approximate);
+        assert_eq!(reading.percentage_or_estimate(), Some(20));
+
+        let reading = BatteryReading::new(status(None, BatteryLevel::Unknown, None), ReadingSource::Notification);
+        assert_eq!(reading.confidence, Confidence::Unknown);
+        assert_eq!(reading.percentage_or_estimate(), None);
+        assert_eq!(reading.source.to_string(), "notification");
+    }
+
+    #[test]
+    fn test_stale_reading() {
+        let mut reading = BatteryReading::new(status(Some(50), BatteryLevel::Good, None),
+                                              ReadingSource::Poll(BatterySourceKind::Solaar));
+        assert!(!reading.is_stale(Duration::from_secs(60)));
+
+        reading.taken_at -= Duration::from_secs(120);
+        assert!(reading.is_stale(Duration::from_secs(60)));
+
+        // The wall clock does not make an old reading fresh
+        reading.timestamp = SystemTime::now();
+        assert!(reading.is_stale(Duration::from_secs(60)));
+
+        // From the future after the clock was set back
+        let mut reading = BatteryReading::new(status(Some(50), BatteryLevel::Good, None), ReadingSource::Notification);
+        reading.timestamp = SystemTime::now() + Duration::from_secs(3600);
+        assert!(reading.is_stale(Duration::from_secs(60)));
+    }
+}
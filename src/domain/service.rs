@@ -1,21 +1,64 @@
-mod hardware {}
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn, error};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::mpsc;
 
-use std::any::Any;
-use std::fmt::{write, Arguments, Display};
-use anyhow::{Context, Result};
-use log::{info, warn, error};
+use crate::adapter::{SolaarAdapter, UPowerReader};
+use crate::config::{BatterySourceKind, Config};
+use crate::hardware::{BatterySource, USBDeviceManager, LogitechManager, PowerManager, PowerSupplyManager, VoltageCurve};
+use crate::hardware::hid::BatteryStatus;
+use crate::hardware::listener::DeviceEvent;
+use crate::hardware::power::ChargingAction;
+use crate::hardware::usb::{PortPowerSwitching, USBManager};
+use super::policy::{ChargingPolicy, ChargingState};
+use super::reading::{BatteryReading, Confidence, ReadingSource};
+use super::state::{PersistedState, StateStore};
 
-use crate::config::Config;
-use crate::hardware::{USBDeviceManager, LogitechManager, PowerManager};
+// Readings older than this are not acted upon, younger ones stand in for
+// sources that do not answer
+const MAX_READING_AGE: Duration = Duration::from_secs(60);
+
+// How often a charging strategy is applied before falling back to the next
+const ATTEMPTS_PER_STRATEGY: usize = 2;
 
 pub struct BatteryManager {
     config: Config,
     usb_manager: USBDeviceManager,
     logitech_manager: LogitechManager,
+    power_supply_manager: PowerSupplyManager,
+    // Wait on another process or the bus, read on blocking threads
+    solaar_adapter: Arc<Mutex<SolaarAdapter>>,
+    upower_reader: Arc<Mutex<UPowerReader>>,
     power_manager: PowerManager,
+    charging_disabled: Option<DisabledCharging>,
+    // Percentage no charging strategy took effect at, not tried again until it changes
+    disabling_failed_at: Option<u8>,
+    // Probed once per device path, hubs do not change their characteristics
+    port_switching: HashMap<String, PortPowerSwitching>,
+    policy: ChargingPolicy,
+    last_reading: Option<BatteryReading>,
+    state_store: StateStore,
+    state: PersistedState,
+    device_info_logged: bool,
+}
+
+/// Charging we disabled, and how, to undo it.
+#[derive(Debug, Clone)]
+struct DisabledCharging {
+    // Disappears from the bus while its port is powered off
+    device: USBManager,
+    action: ChargingAction,
+    // Whether the battery stopped charging, unknown when it cannot be read
+    effective: Option<bool>,
+    since: Instant,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum PowerEvent {
     ChargingEnabling(u8),
     ChargingDisabling(u8),
@@ -29,91 +72,1189 @@ impl std::fmt::Display for PowerEvent {
         match self {
             PowerEvent::ChargingEnabling(v) => write!(f, "charging_enabled, at {}%", v),
             PowerEvent::ChargingDisabling(v) => write!(f, "charging_disabled, at {}%", v),
-            PowerEvent::NoChange(v) => write!(f, "no_change, at {}", v),
-            PowerEvent::Error(e) => {
-                let msg = e.as_ref().unwrap();
-                write!(f, "error: {}", e.as_ref().unwrap())
-            }
+            PowerEvent::NoChange(v) => write!(f, "no_change, at {}%", v),
+            PowerEvent::Error(Some(e)) => write!(f, "error: {}", e),
+            PowerEvent::Error(None) => write!(f, "error"),
         }
     }
 }
 
 impl BatteryManager {
     pub fn new(config: Config) -> Result<Self> {
-        let hid_communicator = LogitechManager::new()
+        let hid_communicator = LogitechManager::new(config.device.hid_backend)
             .context("Failed to initialize HID communicator")?;
+        Self::with_logitech_manager(config, hid_communicator)
+    }
+
+    pub fn with_logitech_manager(config: Config, mut hid_communicator: LogitechManager) -> Result<Self> {
+        let mut solaar_adapter = SolaarAdapter::new(config.solaar.binary.clone(),
+                                                    Duration::from_secs(config.solaar.timeout_secs));
+        if let Some(points) = &config.device.voltage_curve {
+            let voltage_curve = VoltageCurve::new(points.clone())
+                .context("Invalid voltage curve in configuration")?;
+            solaar_adapter.set_voltage_curve(voltage_curve.clone());
+            hid_communicator.set_voltage_curve(voltage_curve);
+        }
+        hid_communicator.set_selector(config.device.hid.clone());
+        hid_communicator.set_bluetooth_product_ids(config.device.bluetooth_product_ids.clone());
+        solaar_adapter.set_bluetooth_product_ids(config.device.bluetooth_product_ids.clone());
+
+        let mut power_supply_manager = PowerSupplyManager::new();
+        power_supply_manager.set_bluetooth_product_ids(config.device.bluetooth_product_ids.clone());
+        power_supply_manager.set_serial_number(config.device.hid.serial_number.clone());
+
+        let upower_reader = UPowerReader::new(
+            config.upower.model.clone().or_else(|| config.device.hid.name.clone()),
+            config.upower.serial.clone().or_else(|| config.device.hid.serial_number.clone()),
+            Duration::from_secs(config.upower.timeout_secs),
+        );
+
+        let policy = ChargingPolicy::new(&config.thresholds)
+            .context("Invalid thresholds in configuration")?;
+        let state_store = StateStore::new(config.state_file.clone());
+        let mut state = state_store.load().unwrap_or_else(|e| {
+            warn!("Starting without saved state: {:#}", e);
+            PersistedState::default()
+        });
+        let device_id = format!("{:04x}:{:04x}", config.device.vendor_id, config.device.product_id);
+        if state.device.as_ref().is_some_and(|saved| *saved != device_id) {
+            // Changes applied to the other device are still restored
+            warn!("Saved charging state belongs to device {}, not {}, starting afresh",
+                  state.device.as_deref().unwrap_or_default(), device_id);
+            state.charging = None;
+        }
+        state.device = Some(device_id);
 
         Ok(Self {
             config,
             usb_manager: USBDeviceManager::new(),
             logitech_manager: hid_communicator,
+            power_supply_manager,
+            solaar_adapter: Arc::new(Mutex::new(solaar_adapter)),
+            upower_reader: Arc::new(Mutex::new(upower_reader)),
             power_manager: PowerManager::new(),
+            charging_disabled: None,
+            disabling_failed_at: None,
+            port_switching: HashMap::new(),
+            policy,
+            last_reading: None,
+            state_store,
+            state,
+            device_info_logged: false,
         })
     }
 
-    pub async fn check_and_manage(&mut self) -> Result<()> {
+    /// Poll every `poll_interval` and react to battery notifications in between,
+    /// until SIGTERM or SIGINT. Charging is restored before returning.
+    ///
+    /// A signal interrupts a check waiting for a charging change to settle,
+    /// changes are saved before waiting and restored all the same.
+    pub async fn run(&mut self, poll_interval: Duration) -> Result<()> {
+        let mut sigterm = signal(SignalKind::terminate()).context("Failed to listen for SIGTERM")?;
+        let mut sigint = signal(SignalKind::interrupt()).context("Failed to listen for SIGINT")?;
+        let mut ticker = tokio::time::interval(poll_interval);
+        let mut events: Option<mpsc::Receiver<DeviceEvent>> = None;
+
+        self.restore_leftover_charging();
+        loop {
+            let interrupted = tokio::select! {
+                signal = shutdown_signal(&mut sigterm, &mut sigint) => Some(signal),
+                _ = ticker.tick() => {
+                    let interrupted = unless_shutdown(async {
+                        if let Err(e) = self.check_and_manage().await {
+                            error!("Error during battery check: {}", e);
+                            self.enable_charging_after_error().await;
+                        }
+                    }, &mut sigterm, &mut sigint).await;
+                    if events.is_none() {
+                        events = self.start_listener();
+                    }
+                    interrupted
+                }
+                event = next_event(&mut events) => match event {
+                    Some(event) => unless_shutdown(async {
+                        if let Err(e) = self.handle_device_event(event).await {
+                            error!("Error while handling device event: {}", e);
+                            self.enable_charging_after_error().await;
+                        }
+                    }, &mut sigterm, &mut sigint).await,
+                    None => {
+                        debug!("Notification listener ended, restarting on next poll");
+                        events = None;
+                        None
+                    }
+                }
+            };
+            if let Some(signal) = interrupted {
+                info!("Received {}, shutting down", signal);
+                break;
+            }
+        }
+
+        self.restore_charging().context("Failed to restore charging on shutdown")
+    }
+
+    /// Restore charging changes a previous run could not undo, e.g. because it crashed.
+    fn restore_leftover_charging(&mut self) {
+        if self.state.charging_actions.is_empty() {
+            return;
+        }
+        warn!("Restoring {} charging change(s) left behind by a previous run", self.state.charging_actions.len());
+        if let Err(e) = self.restore_charging() {
+            error!("{:#}", e);
+        }
+    }
+
+    /// Undo every charging change still in effect, so the device charges
+    /// without us. The saved charging state stays, the next start disables
+    /// charging again if the policy wants it off.
+    fn restore_charging(&mut self) -> Result<()> {
+        let restored = undo_charging_actions(&self.power_manager, &mut self.state);
+        self.charging_disabled = None;
+        self.save_state();
+        restored
+    }
+
+    /// Whether to keep the port off although the battery cannot be read, when
+    /// configured to. The device may only be readable over that port, powering
+    /// it on right away would toggle it on every poll.
+    fn holds_port_off(&self) -> bool {
+        if !self.config.device.hold_port_off {
+            return false;
+        }
+        let hold = Duration::from_secs(self.config.device.port_off_hold_secs);
+        self.charging_disabled.as_ref().is_some_and(|disabled| {
+            disabled.action.strategy().switches_port_power() && disabled.since.elapsed() < hold
+        })
+    }
+
+    /// Never leave charging disabled while the battery cannot be managed.
+    async fn enable_charging_after_error(&mut self) {
+        if let Some(disabled) = self.charging_disabled.clone() {
+            warn!("Battery cannot be monitored while charging is disabled, enabling it again");
+            self.enable_charging(&disabled.device).await;
+        }
+    }
+
+    fn start_listener(&mut self) -> Option<mpsc::Receiver<DeviceEvent>> {
         let device_config = &self.config.device;
+        match self.logitech_manager.start_listener(device_config.vendor_id, device_config.product_id) {
+            Ok(events) => events,
+            Err(e) => {
+                warn!("Failed to start notification listener: {}", e);
+                None
+            }
+        }
+    }
 
-        match self.usb_manager.find_device(device_config.vendor_id, device_config.product_id)? {
+    async fn handle_device_event(&mut self, event: DeviceEvent) -> Result<()> {
+        match event {
+            DeviceEvent::Battery { status, .. } => {
+                match self.find_usb_device()? {
+                    Some(usb_device) => {
+                        let reading = BatteryReading::new(status, ReadingSource::Notification);
+                        self.last_reading = Some(reading.clone());
+                        let new_event = self.event_for_reading(&reading);
+                        info!("is_connected_via_usb=true, source=notification, event: {}", new_event);
+                        self.process_event(new_event, &usb_device).await;
+                    }
+                    None => debug!("Battery notification while not connected via USB: {:?}", status),
+                }
+                Ok(())
+            }
+            DeviceEvent::Connection { device_index, wireless_product_id, connected } => {
+                info!("Device 0x{:04x} in slot {} {}", wireless_product_id, device_index,
+                      if connected { "connected" } else { "disconnected" });
+                if connected {
+                    self.check_and_manage().await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn check_and_manage(&mut self) -> Result<()> {
+        match self.find_usb_device()? {
             Some(usb_device) => {
-                info!("Device found: {} at {}", device_config.name, usb_device.sys_path);
+                let device_config = &self.config.device;
+                info!("Device found: {} at {} (bus {}, device {}), charging_enabled={}",
+                      device_config.name, usb_device.sys_path, usb_device.bus, usb_device.device,
+                      self.power_manager.is_charging_enabled(&usb_device.sys_path, &device_config.charging_strategies));
+                self.logitech_manager.set_usb_device(&usb_device.sys_path);
+                self.log_device_info(&usb_device);
                 let new_event = self.resolve_next_event(&usb_device).await?;
-                info!("is_connected_via_usb=true, event: {}", new_event);
-                self.process_event(new_event, &usb_device)
+                let transport = self.logitech_manager.selected_transport()
+                    .map(|transport| transport.to_string())
+                    .unwrap_or_else(|| "none".to_string());
+                info!("is_connected_via_usb=true, battery_transport={}, event: {}", transport, new_event);
+                self.process_event(new_event, &usb_device).await
             }
             None => {
+                let device_config = &self.config.device;
                 info!("Device not found via USB: vendor_id=0x{:04x}, product_id=0x{:04x}", 
                      device_config.vendor_id, device_config.product_id);
+                self.forget_unplugged_device();
             }
         }
 
         Ok(())
     }
 
-    fn process_event(&self, event: PowerEvent, device: &crate::hardware::usb::USBManager) {
+    /// The device on the bus, or the one we powered off the port of.
+    fn find_usb_device(&self) -> Result<Option<USBManager>> {
+        let device_config = &self.config.device;
+        let usb_device = self.usb_manager.find_device(device_config.vendor_id, device_config.product_id)?;
+        Ok(usb_device.or_else(|| {
+            self.charging_disabled.as_ref()
+                .filter(|disabled| disabled.action.strategy().switches_port_power())
+                .map(|disabled| disabled.device.clone())
+        }))
+    }
+
+    /// Restore what we changed on a device that left the bus with its port on, it was unplugged.
+    fn forget_unplugged_device(&mut self) {
+        let Some(disabled) = &self.charging_disabled else {
+            return;
+        };
+
+        info!("Device at {} unplugged while charging was disabled with {}", disabled.device.sys_path, disabled.action.strategy());
+        if let Err(e) = self.restore_charging() {
+            error!("{:#}", e);
+        }
+    }
+
+    fn log_device_info(&mut self, device: &USBManager) {
+        if self.device_info_logged {
+            return;
+        }
+
+        match self.logitech_manager.get_device_info(device.vendor_id, device.product_id) {
+            Ok(Some(device_info)) => {
+                let firmware = device_info.main_firmware()
+                    .map(|fw| format!("{} {}", fw.name, fw.version))
+                    .unwrap_or_else(|| "unknown".to_string());
+                info!("device_name={}, kind={:?}, model_ids={:04x?}, unit_id={}, serial={}, firmware={}, features={}",
+                      device_info.name.as_deref().unwrap_or("unknown"),
+                      device_info.kind,
+                      device_info.model_ids,
+                      device_info.unit_id.as_deref().unwrap_or("unknown"),
+                      device_info.serial_number.as_deref().unwrap_or("unknown"),
+                      firmware,
+                      device_info.features.len());
+                if !device_info.features.is_empty() && !device_info.has_battery_feature() {
+                    warn!("Device does not report its battery over HID++");
+                }
+                self.device_info_logged = true;
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to read device information: {}", e),
+        }
+    }
+
+    async fn process_event(&mut self, event: PowerEvent, device: &USBManager) {
         match event {
-            PowerEvent::ChargingEnabling(_) => {
-                info!("Charging enabling");
-                self.power_manager.set_charging_enabled(&device.sys_path).unwrap_or({
-                    error!("Failed to enable charging.");
-                });
-                info!("Charging enabled in device at {}", device.sys_path);
-            }
-            PowerEvent::ChargingDisabling(v) => {
-                info!("Charging disabling in device at {}...", device.sys_path);
-                self.power_manager.set_charging_disabled(&device.sys_path).unwrap_or({
-                    error!("Failed to disable charging.");
-                });
-                info!("Charging disabled for device at {}", device.sys_path);
+            PowerEvent::ChargingEnabling(_) => self.enable_charging(device).await,
+            PowerEvent::ChargingDisabling(percentage) if self.disabling_failed_at == Some(percentage) => {
+                info!("No charging strategy took effect at {}%, not trying again until the battery changes", percentage);
             }
+            PowerEvent::ChargingDisabling(percentage) => self.disable_charging(device, percentage).await,
             PowerEvent::NoChange(_) => {
                 info!("Do nothing in device at {}", device.sys_path);
             }
             PowerEvent::Error(_) => {
                 error!("Error occurred in device at {}", device.sys_path);
+                if self.holds_port_off() {
+                    info!("Keeping the port of device at {} off for up to {}s without battery reading",
+                          device.sys_path, self.config.device.port_off_hold_secs);
+                } else {
+                    self.enable_charging_after_error().await;
+                }
             }
         }
     }
 
-    async fn resolve_next_event(&mut self, device: &crate::hardware::usb::USBManager) -> Result<PowerEvent> {
-
-        let battery_level_optional= self.logitech_manager.get_battery_level(
-            device.vendor_id, device.product_id
-        )?;
+    /// Try the configured charging strategies in order until one stops the
+    /// battery charging, retrying each before falling back to the next.
+    async fn disable_charging(&mut self, device: &USBManager, percentage: u8) {
+        info!("Charging disabling in device at {}...", device.sys_path);
+        for strategy in self.config.device.charging_strategies.clone() {
+            if strategy.switches_port_power() {
+                if let Err(e) = self.check_port_cuts_power(device) {
+                    error!("Refusing to disable charging with {} in device at {}: {:#}", strategy, device.sys_path, e);
+                    continue;
+                }
+            }
 
-        let nextEvent: PowerEvent = match Some(battery_level_optional) {
-            Some(battery_level) => {
-                let actual_battery_level: u8 = battery_level.unwrap();
-                let event = if actual_battery_level < self.config.thresholds.high_threshold {
-                    PowerEvent::ChargingEnabling(actual_battery_level)
-                } else {
-                    PowerEvent::ChargingDisabling(actual_battery_level)
+            for attempt in 1..=ATTEMPTS_PER_STRATEGY {
+                let action = match self.power_manager.set_charging_disabled(&device.sys_path, strategy) {
+                    Ok(action) => action,
+                    Err(e) => {
+                        warn!("Failed to disable charging with {}: {:#}", strategy, e);
+                        break;
+                    }
                 };
-                event
+                self.remember_action(&action);
+
+                let effective = self.verify_charging_stopped(device, &action).await;
+                info!("action_done=charging_disabled, strategy={}, attempt={}, action_effective={}",
+                      strategy, attempt, effective.map_or("unknown".to_string(), |effective| effective.to_string()));
+                if effective == Some(false) {
+                    if let Err(e) = self.power_manager.undo(&action) {
+                        // Keep it recorded, so it is undone once charging resumes
+                        error!("Failed to undo ineffective {}: {:#}", strategy, e);
+                    } else {
+                        self.forget_action(&action);
+                        continue;
+                    }
+                }
+
+                self.charging_disabled = Some(DisabledCharging { device: device.clone(), action, effective, since: Instant::now() });
+                self.disabling_failed_at = None;
+                self.save_charging_state(ChargingState::Disabled);
+                return;
+            }
+        }
+        error!("Failed to disable charging in device at {}, no charging strategy took effect", device.sys_path);
+        self.disabling_failed_at = Some(percentage);
+    }
+
+    /// Whether the battery stopped charging once `action` settled.
+    async fn verify_charging_stopped(&mut self, device: &USBManager, action: &ChargingAction) -> Option<bool> {
+        tokio::time::sleep(self.settle_delay()).await;
+        if !self.power_manager.is_applied(action) {
+            warn!("{} did not stick for device at {}", action.strategy(), device.sys_path);
+            return Some(false);
+        }
+
+        match self.read_battery(device.vendor_id, device.product_id).await {
+            Some(reading) => Some(!reading.charging.is_charging()),
+            // Gone from the bus together with the port power
+            None if action.strategy().switches_port_power() => Some(true),
+            None => None,
+        }
+    }
+
+    /// Whether the device gets power again once enabling charging settled.
+    async fn verify_charging_resumed(&mut self, device: &USBManager) -> Option<bool> {
+        tokio::time::sleep(self.settle_delay()).await;
+        if !self.power_manager.is_charging_enabled(&device.sys_path, &self.config.device.charging_strategies) {
+            return Some(false);
+        }
+
+        self.read_battery(device.vendor_id, device.product_id).await
+            .map(|reading| reading.external_power || reading.charging.is_charging())
+    }
+
+    fn settle_delay(&self) -> Duration {
+        Duration::from_millis(self.config.device.settle_delay_ms)
+    }
+
+    /// Whether powering off the port on its own stops charging.
+    fn check_port_cuts_power(&mut self, device: &USBManager) -> Result<()> {
+        let switching = match self.port_switching.get(&device.sys_path) {
+            Some(switching) => *switching,
+            None => {
+                let switching = self.usb_manager.probe_port(&device.sys_path)
+                    .context("Cannot tell whether the hub can switch port power")?;
+                info!("port_power_switching={} for device at {}", switching, device.sys_path);
+                self.port_switching.insert(device.sys_path.clone(), switching);
+                switching
+            }
+        };
+
+        match switching {
+            PortPowerSwitching::Individual => Ok(()),
+            PortPowerSwitching::NotSwitchable => bail!("the hub cannot switch port power, the device would keep charging"),
+            PortPowerSwitching::Ganged => bail!("the hub switches its ports together, the port keeps power while any other port is on"),
+        }
+    }
+
+    async fn enable_charging(&mut self, device: &USBManager) {
+        info!("Charging enabling in device at {}...", device.sys_path);
+        let strategies = self.config.device.charging_strategies.clone();
+        let result = match &self.charging_disabled {
+            Some(disabled) => self.power_manager.undo(&disabled.action),
+            // Disabled before a restart, or by someone else: undo what was saved
+            // with the settings from before, then whatever else is in effect
+            None => self.undo_saved_actions(&device.sys_path)
+                .and_then(|()| self.power_manager.set_charging_enabled(&device.sys_path, &strategies)),
+        };
+        if let Err(e) = result {
+            error!("Failed to enable charging in device at {}: {:#}", device.sys_path, e);
+            return;
+        }
+        self.charging_disabled = None;
+        self.disabling_failed_at = None;
+        self.state.charging_actions.clear();
+        self.save_charging_state(ChargingState::Enabled);
+
+        for attempt in 1..=ATTEMPTS_PER_STRATEGY {
+            let effective = self.verify_charging_resumed(device).await;
+            info!("action_done=charging_enabled, attempt={}, action_effective={}",
+                  attempt, effective.map_or("unknown".to_string(), |effective| effective.to_string()));
+            if effective != Some(false) {
+                return;
+            }
+            // Whatever still keeps it from charging
+            if let Err(e) = self.power_manager.set_charging_enabled(&device.sys_path, &strategies) {
+                warn!("Failed to enable charging again: {:#}", e);
+            }
+        }
+        warn!("Device at {} does not charge after enabling charging, is the cable plugged in?", device.sys_path);
+    }
+
+    fn undo_saved_actions(&self, sys_path: &str) -> Result<()> {
+        self.state.charging_actions.iter()
+            .filter(|action| action.sys_path() == sys_path && self.power_manager.is_applied(action))
+            .try_for_each(|action| self.power_manager.undo(action))
+    }
+
+    /// Save `action` as soon as it is applied, to restore it should we crash.
+    fn remember_action(&mut self, action: &ChargingAction) {
+        if !self.state.charging_actions.contains(action) {
+            self.state.charging_actions.push(action.clone());
+        }
+        self.save_state();
+    }
+
+    fn forget_action(&mut self, action: &ChargingAction) {
+        self.state.charging_actions.retain(|saved| saved != action);
+        self.save_state();
+    }
+
+    fn save_charging_state(&mut self, charging: ChargingState) {
+        self.state.charging = Some(charging);
+        self.save_state();
+    }
+
+    fn save_state(&self) {
+        if let Err(e) = self.state_store.save(&self.state) {
+            warn!("Failed to save charging state to {}: {:#}", self.state_store.path().display(), e);
+        }
+    }
+
+    async fn resolve_next_event(&mut self, device: &USBManager) -> Result<PowerEvent> {
+        if self.state.charging.is_none() {
+            // Nothing saved yet, carry on from what the hardware is doing
+            let enabled = self.power_manager.is_charging_enabled(&device.sys_path, &self.config.device.charging_strategies);
+            self.state.charging = Some(ChargingState::from_enabled(enabled));
+        }
+
+        let reading = match self.read_battery(device.vendor_id, device.product_id).await {
+            Some(reading) => Some(reading),
+            None => self.last_reading.clone().inspect(|reading| {
+                info!("No battery source answered, using the last reading from {}", reading.source);
+            }),
+        };
+        let event = match reading {
+            Some(reading) => {
+                self.track_disabled_charging(&reading);
+                match self.event_for_reading(&reading) {
+                    PowerEvent::NoChange(percentage)
+                        if reading.confidence >= Confidence::Estimated && self.charging_resumed_behind_our_back(device) => {
+                        warn!("Charging is disabled but device at {} is allowed to charge again, disabling it again",
+                              device.sys_path);
+                        PowerEvent::ChargingDisabling(percentage)
+                    }
+                    event => event,
+                }
+            }
+            None => PowerEvent::Error(Some("no battery reading from any source".to_string())),
+        };
+
+        Ok(event)
+    }
+
+    /// Whether charging is disabled in the saved state while the device may
+    /// charge, e.g. after a reboot or another tool enabled it.
+    fn charging_resumed_behind_our_back(&self, device: &USBManager) -> bool {
+        self.state.charging == Some(ChargingState::Disabled)
+            && self.power_manager.is_charging_enabled(&device.sys_path, &self.config.device.charging_strategies)
+    }
+
+    /// Keep track of whether disabled charging still keeps the battery from charging.
+    fn track_disabled_charging(&mut self, reading: &BatteryReading) {
+        let Some(disabled) = &mut self.charging_disabled else {
+            return;
+        };
+
+        let effective = !reading.charging.is_charging();
+        if disabled.effective != Some(effective) {
+            disabled.effective = Some(effective);
+            if effective {
+                info!("action_effective=true, battery stopped charging after {}", disabled.action.strategy());
+            } else {
+                warn!("action_effective=false, battery charges again despite {}", disabled.action.strategy());
+            }
+        }
+    }
+
+    /// Try the configured battery sources in order, the first reading wins.
+    async fn read_battery(&mut self, vendor_id: u16, product_id: u16) -> Option<BatteryReading> {
+        for kind in self.config.device.battery_sources.clone() {
+            let result = match kind {
+                BatterySourceKind::Hidpp => self.logitech_manager.read_battery(vendor_id, product_id),
+                BatterySourceKind::PowerSupply => self.power_supply_manager.read_battery(vendor_id, product_id),
+                BatterySourceKind::Solaar => read_blocking(&self.solaar_adapter, vendor_id, product_id).await,
+                BatterySourceKind::Upower => read_blocking(&self.upower_reader, vendor_id, product_id).await,
+            };
+            match result {
+                Ok(Some(status)) => {
+                    info!("battery_source={}", kind);
+                    let reading = BatteryReading::new(status, ReadingSource::Poll(kind));
+                    self.last_reading = Some(reading.clone());
+                    return Some(reading);
+                }
+                Ok(None) => debug!("No battery reading from {}", kind),
+                Err(e) => warn!("Failed to read battery from {}: {:#}", kind, e),
+            }
+        }
+
+        None
+    }
+
+    fn event_for_reading(&self, reading: &BatteryReading) -> PowerEvent {
+        info!("is_charging={}, charging_status={:?}, level={:?}, external_power={}, confidence={}",
+              reading.charging.is_charging(), reading.charging,
+              reading.level, reading.external_power, reading.confidence);
+        if reading.is_stale(MAX_READING_AGE) {
+            return PowerEvent::Error(Some(format!("stale battery reading from {}", reading.source)));
+        }
+        let Some(actual_battery_level) = reading.percentage_or_estimate() else {
+            return PowerEvent::Error(Some(format!("no battery percentage or level from {}", reading.source)));
+        };
+        // Charging is on unless we know otherwise
+        let state = self.state.charging.unwrap_or(ChargingState::Enabled);
+        match self.policy.next_event(state, actual_battery_level) {
+            // A level bucket is too coarse to stop charging on, resuming it is always safe
+            PowerEvent::ChargingDisabling(percentage) if reading.confidence < Confidence::Estimated => {
+                info!("Not disabling charging on a {} reading of {}%", reading.confidence, percentage);
+                PowerEvent::NoChange(percentage)
+            }
+            event => event,
+        }
+    }
+}
+
+/// Undo what a previous run left behind, and charging strategies still in
+/// effect on the device, for the `restore` command. Works without HID access.
+pub fn restore(config: &Config) -> Result<()> {
+    restore_with(config, &USBDeviceManager::new())
+}
+
+fn restore_with(config: &Config, usb_manager: &USBDeviceManager) -> Result<()> {
+    let power_manager = PowerManager::new();
+    let state_store = StateStore::new(config.state_file.clone());
+    let mut state = state_store.load().unwrap_or_else(|e| {
+        warn!("Restoring without saved state: {:#}", e);
+        PersistedState::default()
+    });
+
+    let restored = undo_charging_actions(&power_manager, &mut state);
+    if let Err(e) = state_store.save(&state) {
+        warn!("Failed to save charging state to {}: {:#}", state_store.path().display(), e);
+    }
+    let device_config = &config.device;
+    if let Some(device) = usb_manager.find_device(device_config.vendor_id, device_config.product_id)? {
+        power_manager.set_charging_enabled(&device.sys_path, &device_config.charging_strategies)
+            .with_context(|| format!("Failed to enable charging in device at {}", device.sys_path))?;
+    }
+    restored
+}
+
+/// Undo the charging changes saved in `state`. Changes that cannot be undone
+/// stay saved for the next start.
+fn undo_charging_actions(power_manager: &PowerManager, state: &mut PersistedState) -> Result<()> {
+    let mut failed = Vec::new();
+    for action in std::mem::take(&mut state.charging_actions) {
+        // Settings of the device itself are gone with it, a replugged device starts afresh
+        if !action.strategy().switches_port_power() && !Path::new(action.sys_path()).exists() {
+            info!("Device at {} is gone, nothing to restore for {}", action.sys_path(), action.strategy());
+            continue;
+        }
+        match power_manager.undo(&action) {
+            Ok(()) => info!("action_done=charging_restored, strategy={}, device at {}",
+                            action.strategy(), action.sys_path()),
+            Err(e) => {
+                error!("Failed to restore {} in device at {}: {:#}", action.strategy(), action.sys_path(), e);
+                failed.push(action);
             }
-            None => PowerEvent::Error(None)
+        }
+    }
+
+    let remaining = failed.len();
+    state.charging_actions = failed;
+    if remaining > 0 {
+        bail!("{} charging change(s) could not be restored", remaining);
+    }
+    Ok(())
+}
+
+/// The name of the first of SIGTERM and SIGINT to arrive.
+async fn shutdown_signal(sigterm: &mut Signal, sigint: &mut Signal) -> &'static str {
+    tokio::select! {
+        _ = sigterm.recv() => "SIGTERM",
+        _ = sigint.recv() => "SIGINT",
+    }
+}
+
+/// Run `work` to completion unless a shutdown signal arrives first, whose name is returned.
+async fn unless_shutdown(work: impl Future<Output = ()>, sigterm: &mut Signal, sigint: &mut Signal) -> Option<&'static str> {
+    tokio::select! {
+        () = work => None,
+        signal = shutdown_signal(sigterm, sigint) => Some(signal),
+    }
+}
+
+/// Read the battery from `source` on a blocking thread, keeping the runtime
+/// free to handle signals while it waits on another process or the bus.
+async fn read_blocking<S>(source: &Arc<Mutex<S>>, vendor_id: u16, product_id: u16) -> Result<Option<BatteryStatus>>
+where
+    S: BatterySource + Send + 'static,
+{
+    let source = Arc::clone(source);
+    tokio::task::spawn_blocking(move || {
+        // A read that panicked left nothing half done
+        source.lock().unwrap_or_else(PoisonError::into_inner).read_battery(vendor_id, product_id)
+    })
+    .await
+    .context("Battery read did not finish")?
+}
+
+async fn next_event(events: &mut Option<mpsc::Receiver<DeviceEvent>>) -> Option<DeviceEvent> {
+    match events {
+        Some(events) => events.recv().await,
+        // No listener running, only the poll timer can fire
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ChargingStrategy;
+    use crate::hardware::hid::{BatteryLevel, BatteryStatus, ChargingStatus, LOGITECH_VENDOR_ID};
+    use crate::hardware::mock::{MockBattery, MockDevice, MockTransport};
+    use crate::adapter::mock::FakeSolaar;
+    use std::fs;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    const PRODUCT_ID: u16 = 0xC548;
+
+    /// Device 1-4.2 on port 2 of hub 1-4, as under /sys/bus/usb/devices,
+    /// next to the state file of the daemon managing it.
+    struct FakeUsbDevice {
+        dir: TempDir,
+    }
+
+    impl FakeUsbDevice {
+        fn new(charging_enabled: bool) -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            fs::create_dir_all(dir.path().join("1-4/1-4:1.0/1-4-port2")).unwrap();
+            fs::create_dir_all(dir.path().join("1-4.2")).unwrap();
+            let usb = Self { dir };
+            usb.set_charging_enabled(charging_enabled);
+            usb
+        }
+
+        fn path(&self, path: &str) -> PathBuf {
+            self.dir.path().join(path)
+        }
+
+        fn config(&self) -> Config {
+            let mut config = Config::default();
+            config.device.vendor_id = LOGITECH_VENDOR_ID;
+            config.device.product_id = PRODUCT_ID;
+            config.thresholds.high_threshold = 80;
+            config.thresholds.low_threshold = 20;
+            config.state_file = self.path("state.json");
+            config.device.settle_delay_ms = 0;
+            config
+        }
+
+        fn usb_device(&self) -> USBManager {
+            USBManager { sys_path: self.path("1-4.2").to_string_lossy().to_string(), ..usb_device() }
+        }
+
+        fn charging_enabled(&self) -> bool {
+            fs::read_to_string(self.path("1-4/1-4:1.0/1-4-port2/disable")).unwrap().trim() == "0"
+        }
+
+        fn set_charging_enabled(&self, enabled: bool) {
+            fs::write(self.path("1-4/1-4:1.0/1-4-port2/disable"), if enabled { "0\n" } else { "1\n" }).unwrap();
+        }
+
+        /// Plug `device` in here, it charges while the port is on and it is authorized.
+        fn plug_in(&self, device: &MockDevice) -> MockDevice {
+            let disable = self.path("1-4/1-4:1.0/1-4-port2/disable");
+            let authorized = self.path("1-4.2/authorized");
+            device.set_powered(move || {
+                let read = |path: &PathBuf| fs::read_to_string(path).map(|value| value.trim().to_string());
+                read(&disable).is_ok_and(|value| value == "0") && read(&authorized).map_or(true, |value| value != "0")
+            });
+            device.clone()
+        }
+    }
+
+    /// One battery check against `usb`, as `check_and_manage` does it.
+    async fn check(manager: &mut BatteryManager, usb: &FakeUsbDevice) -> PowerEvent {
+        // No usbfs to read the hub descriptor from
+        manager.port_switching.entry(usb.usb_device().sys_path).or_insert(PortPowerSwitching::Individual);
+        let event = manager.resolve_next_event(&usb.usb_device()).await.unwrap();
+        manager.process_event(event.clone(), &usb.usb_device()).await;
+        event
+    }
+
+    fn battery_manager_with_config(device: &MockDevice, config: Config) -> BatteryManager {
+        let mut transport = MockTransport::new();
+        transport.add_device(LOGITECH_VENDOR_ID, PRODUCT_ID, "/dev/hidraw0", device.clone());
+        BatteryManager::with_logitech_manager(config, LogitechManager::with_transport(Box::new(transport))).unwrap()
+    }
+
+    fn battery_manager(device: &MockDevice, usb: &FakeUsbDevice) -> BatteryManager {
+        battery_manager_with_config(device, usb.config())
+    }
+
+    fn usb_device() -> USBManager {
+        USBManager {
+            bus: 1,
+            device: 4,
+            vendor_id: LOGITECH_VENDOR_ID,
+            product_id: PRODUCT_ID,
+            sys_path: "/sys/bus/usb/devices/1-4".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve_next_event_from_mock_device() {
+        let device = MockDevice::new(MockBattery::charging(42));
+        let usb = FakeUsbDevice::new(true);
+        let mut manager = battery_manager(&device, &usb);
+
+        let event = manager.resolve_next_event(&usb_device()).await.unwrap();
+        assert_eq!(event, PowerEvent::NoChange(42));
+
+        device.set_battery(MockBattery::charging(85));
+        let event = manager.resolve_next_event(&usb_device()).await.unwrap();
+        assert_eq!(event, PowerEvent::ChargingDisabling(85));
+    }
+
+    // Scenarios from the test design, with the thresholds of the default configuration
+
+    #[tokio::test]
+    async fn test_scenario_high_battery_disables_charging() {
+        let usb = FakeUsbDevice::new(true);
+        let mut manager = battery_manager(&usb.plug_in(&MockDevice::new(MockBattery::charging(85))), &usb);
+
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::ChargingDisabling(85));
+        assert!(!usb.charging_enabled());
+    }
+
+    #[tokio::test]
+    async fn test_scenario_low_battery_enables_charging() {
+        // Deviates from the test design: charging only resumes at or below
+        // low_threshold, 75% is not low enough to resume at the default 20%
+        let device = MockDevice::new(MockBattery::discharging(75));
+        let usb = FakeUsbDevice::new(false);
+        let mut manager = battery_manager(&device, &usb);
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::NoChange(75));
+        assert!(!usb.charging_enabled());
+
+        device.set_battery(MockBattery::discharging(20));
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::ChargingEnabling(20));
+        assert!(usb.charging_enabled());
+    }
+
+    #[tokio::test]
+    async fn test_scenario_battery_at_threshold() {
+        // Deviates from the test design: charging stops at the high threshold,
+        // not only above it
+        let usb = FakeUsbDevice::new(true);
+        let mut manager = battery_manager(&usb.plug_in(&MockDevice::new(MockBattery::charging(80))), &usb);
+
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::ChargingDisabling(80));
+        assert!(!usb.charging_enabled());
+    }
+
+    #[tokio::test]
+    async fn test_scenario_device_not_found() {
+        let device = MockDevice::new(MockBattery::charging(85));
+        let usb = FakeUsbDevice::new(true);
+        let mut manager = battery_manager(&device, &usb);
+        // Nothing with a uevent of the configured device
+        manager.usb_manager = USBDeviceManager::with_root(usb.dir.path());
+
+        manager.check_and_manage().await.unwrap();
+        assert!(usb.charging_enabled());
+        assert!(device.requests().is_empty());
+        assert_eq!(manager.state.charging, None);
+    }
+
+    #[tokio::test]
+    async fn test_scenario_between_thresholds_keeps_state() {
+        let device = MockDevice::new(MockBattery::charging(79));
+        let usb = FakeUsbDevice::new(true);
+        let mut manager = battery_manager(&device, &usb);
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::NoChange(79));
+        assert!(usb.charging_enabled());
+
+        let usb = FakeUsbDevice::new(false);
+        let mut manager = battery_manager(&device, &usb);
+        for percentage in [80, 50, 21] {
+            device.set_battery(MockBattery::discharging(percentage));
+            assert_eq!(check(&mut manager, &usb).await, PowerEvent::NoChange(percentage));
+            assert!(!usb.charging_enabled());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hysteresis_cycle_survives_restart() {
+        let usb = FakeUsbDevice::new(true);
+        let device = usb.plug_in(&MockDevice::new(MockBattery::charging(70)));
+        let config = usb.config();
+        let mut manager = battery_manager_with_config(&device, config.clone());
+
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::NoChange(70));
+        device.set_battery(MockBattery::charging(80));
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::ChargingDisabling(80));
+        device.set_battery(MockBattery::discharging(79));
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::NoChange(79));
+
+        // Restarted with charging still disabled
+        let mut manager = battery_manager_with_config(&device, config.clone());
+        device.set_battery(MockBattery::discharging(40));
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::NoChange(40));
+        assert!(!usb.charging_enabled());
+
+        // Something else resumed the device, it is disabled again
+        usb.set_charging_enabled(true);
+        let mut manager = battery_manager_with_config(&device, config.clone());
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::ChargingDisabling(40));
+        assert!(!usb.charging_enabled());
+
+        device.set_battery(MockBattery::discharging(20));
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::ChargingEnabling(20));
+        assert!(usb.charging_enabled());
+        device.set_battery(MockBattery::charging(60));
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::NoChange(60));
+    }
+
+    #[tokio::test]
+    async fn test_saved_state_belongs_to_the_configured_device() {
+        let device = MockDevice::new(MockBattery::discharging(90));
+        let usb = FakeUsbDevice::new(true);
+        let config = usb.config();
+        let mut manager = battery_manager_with_config(&device, config.clone());
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::ChargingDisabling(90));
+
+        let mut other = config.clone();
+        other.device.product_id = 0xB369;
+        let manager = battery_manager_with_config(&device, other);
+        assert_eq!(manager.state.charging, None);
+        assert_eq!(manager.state.charging_actions.len(), 1);
+
+        let manager = battery_manager_with_config(&device, config);
+        assert_eq!(manager.state.charging, Some(ChargingState::Disabled));
+    }
+
+    #[tokio::test]
+    async fn test_restores_power_when_battery_unreadable_while_off() {
+        let usb = FakeUsbDevice::new(true);
+        let device = usb.plug_in(&MockDevice::new(MockBattery::charging(90)));
+        let mut manager = battery_manager(&device, &usb);
+
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::ChargingDisabling(90));
+        assert!(!usb.charging_enabled());
+        assert_eq!(manager.charging_disabled.as_ref().map(|disabled| (disabled.device.sys_path.clone(), disabled.action.strategy())),
+                   Some((usb.usb_device().sys_path, ChargingStrategy::PortDisable)));
+
+        // Only reachable over USB, gone while the port is off for longer than a reading lasts
+        device.unplug();
+        manager.last_reading.as_mut().unwrap().taken_at -= MAX_READING_AGE * 2;
+        assert!(matches!(check(&mut manager, &usb).await, PowerEvent::Error(_)));
+        assert!(usb.charging_enabled());
+        assert!(manager.charging_disabled.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_holds_port_off_when_configured() {
+        let usb = FakeUsbDevice::new(true);
+        let device = usb.plug_in(&MockDevice::new(MockBattery::charging(90)));
+        let mut config = usb.config();
+        config.device.hold_port_off = true;
+        let mut manager = battery_manager_with_config(&device, config);
+
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::ChargingDisabling(90));
+        device.unplug();
+        manager.last_reading.as_mut().unwrap().taken_at -= MAX_READING_AGE * 2;
+        assert!(matches!(check(&mut manager, &usb).await, PowerEvent::Error(_)));
+        assert!(!usb.charging_enabled());
+
+        // Powered on to read it once the hold is over
+        let hold = Duration::from_secs(manager.config.device.port_off_hold_secs);
+        manager.charging_disabled.as_mut().unwrap().since -= hold;
+        assert!(matches!(check(&mut manager, &usb).await, PowerEvent::Error(_)));
+        assert!(usb.charging_enabled());
+        assert!(manager.charging_disabled.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_refuses_ports_that_cannot_cut_power() {
+        let usb = FakeUsbDevice::new(true);
+        let device = usb.plug_in(&MockDevice::new(MockBattery::charging(90)));
+        let mut manager = battery_manager(&device, &usb);
+
+        manager.port_switching.insert(usb.usb_device().sys_path, PortPowerSwitching::NotSwitchable);
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::ChargingDisabling(90));
+        assert!(usb.charging_enabled());
+
+        manager.port_switching.insert(usb.usb_device().sys_path, PortPowerSwitching::Ganged);
+        device.set_battery(MockBattery::charging(91));
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::ChargingDisabling(91));
+        assert!(usb.charging_enabled());
+        assert!(manager.charging_disabled.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_refuses_ports_of_hubs_that_cannot_be_probed() {
+        let device = MockDevice::new(MockBattery::discharging(90));
+        let usb = FakeUsbDevice::new(true);
+        let mut manager = battery_manager(&device, &usb);
+
+        // No usbfs node to read the hub descriptor through
+        let event = manager.resolve_next_event(&usb.usb_device()).await.unwrap();
+        assert_eq!(event, PowerEvent::ChargingDisabling(90));
+        manager.process_event(event, &usb.usb_device()).await;
+        assert!(usb.charging_enabled());
+        assert!(manager.charging_disabled.is_none());
+        assert!(manager.port_switching.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_to_next_charging_strategy() {
+        let usb = FakeUsbDevice::new(true);
+        let device = usb.plug_in(&MockDevice::new(MockBattery::charging(90)));
+        let mut config = usb.config();
+        config.device.charging_strategies = vec![ChargingStrategy::Deauthorize, ChargingStrategy::PortDisable];
+        let mut manager = battery_manager_with_config(&device, config);
+
+        // No authorized attribute to write
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::ChargingDisabling(90));
+        assert!(!usb.charging_enabled());
+        device.set_battery(MockBattery::discharging(20));
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::ChargingEnabling(20));
+        assert!(usb.charging_enabled());
+
+        let authorized = usb.path("1-4.2/authorized");
+        fs::write(&authorized, "1\n").unwrap();
+        device.set_battery(MockBattery::charging(95));
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::ChargingDisabling(95));
+        assert_eq!(fs::read_to_string(&authorized).unwrap(), "0");
+        assert!(usb.charging_enabled());
+
+        // Undone after a restart, without the recorded action
+        let mut manager = battery_manager_with_config(&device, manager.config.clone());
+        device.set_battery(MockBattery::discharging(10));
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::ChargingEnabling(10));
+        assert_eq!(fs::read_to_string(&authorized).unwrap(), "1");
+    }
+
+    #[tokio::test]
+    async fn test_undoes_strategy_when_battery_keeps_charging() {
+        // Reachable over Bluetooth, charging no matter what happens to the port
+        let device = MockDevice::new(MockBattery::charging(90));
+        let usb = FakeUsbDevice::new(true);
+        let mut manager = battery_manager(&device, &usb);
+
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::ChargingDisabling(90));
+        assert!(usb.charging_enabled());
+        assert!(manager.charging_disabled.is_none());
+        assert_eq!(manager.state.charging, Some(ChargingState::Enabled));
+
+        // Charging from the port now, but not power cycled again until the battery changes
+        usb.plug_in(&device);
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::ChargingDisabling(90));
+        assert!(usb.charging_enabled());
+        device.set_battery(MockBattery::charging(89));
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::ChargingDisabling(89));
+        assert!(!usb.charging_enabled());
+        assert_eq!(manager.charging_disabled.as_ref().and_then(|disabled| disabled.effective), Some(true));
+
+        // Charging again behind our back, e.g. plugged into another port
+        device.set_powered(|| true);
+        device.set_battery(MockBattery::charging(85));
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::NoChange(85));
+        assert_eq!(manager.charging_disabled.as_ref().and_then(|disabled| disabled.effective), Some(false));
+    }
+
+    #[tokio::test]
+    async fn test_restores_charging_left_behind_by_crash() {
+        let device = MockDevice::new(MockBattery::discharging(90));
+        let usb = FakeUsbDevice::new(true);
+        let config = usb.config();
+        let mut manager = battery_manager_with_config(&device, config.clone());
+
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::ChargingDisabling(90));
+        assert!(!usb.charging_enabled());
+        let saved = StateStore::new(&config.state_file).load().unwrap();
+        assert_eq!(saved.charging_actions.iter().map(|action| action.strategy()).collect::<Vec<_>>(),
+                   vec![ChargingStrategy::PortDisable]);
+
+        // Killed without a chance to clean up
+        drop(manager);
+        let mut manager = battery_manager_with_config(&device, config.clone());
+        manager.restore_leftover_charging();
+        assert!(usb.charging_enabled());
+        let saved = StateStore::new(&config.state_file).load().unwrap();
+        assert_eq!((saved.charging, saved.charging_actions), (Some(ChargingState::Disabled), Vec::new()));
+
+        // Disabled again as the policy still wants it, then shut down cleanly
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::ChargingDisabling(90));
+        assert!(!usb.charging_enabled());
+        manager.restore_charging().unwrap();
+        assert!(usb.charging_enabled());
+        assert!(manager.charging_disabled.is_none());
+        let saved = StateStore::new(&config.state_file).load().unwrap();
+        assert_eq!((saved.charging, saved.charging_actions), (Some(ChargingState::Disabled), Vec::new()));
+    }
+
+    #[tokio::test]
+    async fn test_restore_command_needs_no_hid_device() {
+        let device = MockDevice::new(MockBattery::discharging(90));
+        let usb = FakeUsbDevice::new(true);
+        fs::write(usb.path("1-4.2/uevent"), "PRODUCT=46d/c548/1201\n").unwrap();
+        fs::create_dir_all(usb.path("1-4.2/power")).unwrap();
+        fs::write(usb.path("1-4.2/power/control"), "on\n").unwrap();
+        fs::write(usb.path("1-4.2/power/autosuspend_delay_ms"), "500\n").unwrap();
+        let mut config = usb.config();
+        config.device.charging_strategies = vec![ChargingStrategy::RuntimeSuspend];
+        let mut manager = battery_manager_with_config(&device, config.clone());
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::ChargingDisabling(90));
+        assert_eq!(fs::read_to_string(usb.path("1-4.2/power/control")).unwrap(), "auto");
+        drop(manager);
+
+        // Back to the recorded settings, not just whatever lets it charge
+        restore_with(&config, &USBDeviceManager::with_root(usb.dir.path())).unwrap();
+        assert_eq!(fs::read_to_string(usb.path("1-4.2/power/control")).unwrap(), "on");
+        assert_eq!(fs::read_to_string(usb.path("1-4.2/power/autosuspend_delay_ms")).unwrap(), "500");
+        let saved = StateStore::new(&config.state_file).load().unwrap();
+        assert_eq!((saved.charging, saved.charging_actions), (Some(ChargingState::Disabled), Vec::new()));
+    }
+
+    #[tokio::test]
+    async fn test_unplugged_device_forgets_disabled_charging() {
+        let device = MockDevice::new(MockBattery::discharging(90));
+        let usb = FakeUsbDevice::new(true);
+        let mut config = usb.config();
+        config.device.charging_strategies = vec![ChargingStrategy::Deauthorize];
+        let mut manager = battery_manager_with_config(&device, config);
+
+        fs::write(usb.path("1-4.2/authorized"), "1\n").unwrap();
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::ChargingDisabling(90));
+        assert!(manager.charging_disabled.is_some());
+
+        // Still on the bus while deauthorized, so gone means unplugged
+        fs::remove_dir_all(usb.path("1-4.2")).unwrap();
+        manager.forget_unplugged_device();
+        assert!(manager.charging_disabled.is_none());
+        assert!(manager.state.charging_actions.is_empty());
+        assert_eq!(manager.state.charging, Some(ChargingState::Disabled));
+    }
+
+    #[tokio::test]
+    async fn test_no_battery_reading_is_an_error() {
+        let device = MockDevice::new(MockBattery::charging(42));
+        device.unplug();
+        let usb = FakeUsbDevice::new(true);
+        let mut config = usb.config();
+        config.device.battery_sources = vec![BatterySourceKind::Hidpp];
+        let mut manager = battery_manager_with_config(&device, config);
+
+        let event = manager.resolve_next_event(&usb_device()).await.unwrap();
+        assert!(matches!(event, PowerEvent::Error(Some(_))));
+    }
+
+    #[test]
+    fn test_unusable_readings_are_errors() {
+        let usb = FakeUsbDevice::new(true);
+        let manager = battery_manager(&MockDevice::new(MockBattery::charging(42)), &usb);
+        let status = BatteryStatus {
+            percentage: None,
+            level: BatteryLevel::Unknown,
+            charging: ChargingStatus::Discharging,
+            external_power: false,
+            voltage_mv: None,
+        };
+
+        let reading = BatteryReading::new(status.clone(), ReadingSource::Notification);
+        assert_eq!(manager.event_for_reading(&reading),
+                   PowerEvent::Error(Some("no battery percentage or level from notification".to_string())));
+
+        let mut reading = BatteryReading::new(BatteryStatus { percentage: Some(90), ..status.clone() },
+                                              ReadingSource::Poll(BatterySourceKind::Hidpp));
+        assert_eq!(manager.event_for_reading(&reading), PowerEvent::ChargingDisabling(90));
+        reading.taken_at -= MAX_READING_AGE * 2;
+        assert_eq!(manager.event_for_reading(&reading),
+                   PowerEvent::Error(Some("stale battery reading from hidpp".to_string())));
+    }
+
+    #[test]
+    fn test_approximate_readings_do_not_disable_charging() {
+        let usb = FakeUsbDevice::new(true);
+        let mut manager = battery_manager(&MockDevice::new(MockBattery::charging(42)), &usb);
+        let status = |level| BatteryStatus {
+            percentage: None,
+            level,
+            charging: ChargingStatus::Discharging,
+            external_power: false,
+            voltage_mv: None,
         };
 
-        Ok(nextEvent)
+        let reading = BatteryReading::new(status(BatteryLevel::Full), ReadingSource::Notification);
+        assert_eq!(reading.confidence, Confidence::Approximate);
+        assert_eq!(manager.event_for_reading(&reading), PowerEvent::NoChange(90));
+
+        manager.state.charging = Some(ChargingState::Disabled);
+        let reading = BatteryReading::new(status(BatteryLevel::Critical), ReadingSource::Notification);
+        assert_eq!(manager.event_for_reading(&reading), PowerEvent::ChargingEnabling(5));
+    }
+
+    #[tokio::test]
+    async fn test_last_reading_stands_in_until_stale() {
+        let device = MockDevice::new(MockBattery::discharging(50));
+        let usb = FakeUsbDevice::new(true);
+        let mut config = usb.config();
+        config.device.battery_sources = vec![BatterySourceKind::Hidpp];
+        let mut manager = battery_manager_with_config(&device, config);
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::NoChange(50));
+
+        device.unplug();
+        assert_eq!(check(&mut manager, &usb).await, PowerEvent::NoChange(50));
+
+        manager.last_reading.as_mut().unwrap().taken_at -= MAX_READING_AGE * 2;
+        assert_eq!(check(&mut manager, &usb).await,
+                   PowerEvent::Error(Some("stale battery reading from hidpp".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_to_next_battery_source() {
+        let solaar = FakeSolaar::new("echo 'MX Keys Mini'; echo '     USB id       : 046d:C548'; echo '     Battery: 33%, 0.'");
+        let usb = FakeUsbDevice::new(true);
+        let mut config = usb.config();
+        config.device.battery_sources = vec![BatterySourceKind::Hidpp, BatterySourceKind::Solaar];
+        config.solaar.binary = solaar.path().to_string_lossy().to_string();
+        let device = MockDevice::new(MockBattery::discharging(70));
+        let mut manager = battery_manager_with_config(&device, config);
+
+        let reading = manager.read_battery(LOGITECH_VENDOR_ID, PRODUCT_ID).await.unwrap();
+        assert_eq!(reading.percentage, Some(70));
+        assert_eq!(reading.source, ReadingSource::Poll(BatterySourceKind::Hidpp));
+
+        // Unplugged from the HID++ point of view, Solaar still answers
+        device.unplug();
+        let reading = manager.read_battery(LOGITECH_VENDOR_ID, PRODUCT_ID).await.unwrap();
+        assert_eq!(reading.percentage, Some(33));
+        assert_eq!(reading.source, ReadingSource::Poll(BatterySourceKind::Solaar));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,92 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::hardware::power::ChargingAction;
+use super::policy::ChargingState;
+
+/// What the daemon remembers across restarts.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PersistedState {
+    // vendor:product ID of the device the charging state belongs to
+    #[serde(default)]
+    pub device: Option<String>,
+    // Unknown until the first charging change
+    #[serde(default)]
+    pub charging: Option<ChargingState>,
+    // Applied and not undone yet, restored on the next start after a crash
+    #[serde(default)]
+    pub charging_actions: Vec<ChargingAction>,
+}
+
+/// Keeps `PersistedState` in a JSON file.
+pub struct StateStore {
+    path: PathBuf,
+}
+
+impl StateStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Load the saved state, the default state when nothing was saved yet.
+    pub fn load(&self) -> Result<PersistedState> {
+        match fs::read_to_string(&self.path) {
+            Ok(content) => serde_json::from_str(&content)
+                .with_context(|| format!("Failed to parse state file {}", self.path.display())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(PersistedState::default()),
+            Err(e) => Err(e).with_context(|| format!("Failed to read state file {}", self.path.display())),
+        }
+    }
+
+    pub fn save(&self, state: &PersistedState) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+
+        // Replace atomically, a crash must not leave a truncated file behind
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(state)?)
+            .with_context(|| format!("Failed to write {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("Failed to replace state file {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = StateStore::new(dir.path().join("nested").join("state.json"));
+
+        assert_eq!(store.load().unwrap(), PersistedState::default());
+
+        let state = PersistedState {
+            device: Some("046d:c548".to_string()),
+            charging: Some(ChargingState::Disabled),
+            charging_actions: vec![ChargingAction::PortDisable { sys_path: "/sys/bus/usb/devices/1-4.2".to_string() }],
+        };
+        store.save(&state).unwrap();
+        assert_eq!(store.load().unwrap(), state);
+        let content = fs::read_to_string(store.path()).unwrap();
+        assert!(content.contains("\"disabled\""));
+        assert!(content.contains("\"port_disable\""));
+
+        // Written before charging actions were saved
+        fs::write(store.path(), r#"{ "charging": "enabled" }"#).unwrap();
+        assert!(store.load().unwrap().charging_actions.is_empty());
+
+        fs::write(store.path(), "{ not json").unwrap();
+        assert!(store.load().is_err());
+    }
+}
@@ -0,0 +1,291 @@
+use anyhow::{bail, Result};
+
+use super::hidpp::{ReportType, SupportedReports, HIDPP_USAGE_PAGES};
+
+// Item types, bits 2-3 of the prefix
+const TYPE_MAIN: u8 = 0;
+const TYPE_GLOBAL: u8 = 1;
+const TYPE_LOCAL: u8 = 2;
+
+// Item tags, bits 4-7 of the prefix
+const MAIN_INPUT: u8 = 0x08;
+const MAIN_OUTPUT: u8 = 0x09;
+const MAIN_FEATURE: u8 = 0x0B;
+const MAIN_COLLECTION: u8 = 0x0A;
+const MAIN_END_COLLECTION: u8 = 0x0C;
+const GLOBAL_USAGE_PAGE: u8 = 0x00;
+const GLOBAL_REPORT_ID: u8 = 0x08;
+const GLOBAL_PUSH: u8 = 0x0A;
+const GLOBAL_POP: u8 = 0x0B;
+const LOCAL_USAGE: u8 = 0x00;
+
+const ITEM_LONG: u8 = 0xFE;
+
+/// A top-level collection and the report IDs declared inside it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Collection {
+    pub usage_page: u16,
+    pub usage: u16,
+    pub report_ids: Vec<u8>,
+}
+
+/// The parts of a HID report descriptor needed to tell interfaces apart.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReportDescriptor {
+    pub collections: Vec<Collection>,
+    // Every report ID declared, in or outside of a collection
+    pub report_ids: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct GlobalState {
+    usage_page: u16,
+    report_id: Option<u8>,
+}
+
+impl ReportDescriptor {
+    /// Parse a raw report descriptor as returned by the kernel.
+    ///
+    /// Only usage pages, usages, report IDs and the collection nesting are
+    /// interpreted, every other item is skipped.
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let mut descriptor = ReportDescriptor::default();
+        let mut global = GlobalState::default();
+        let mut global_stack = Vec::new();
+        let mut usage: Option<(Option<u16>, u16)> = None;
+        let mut depth = 0usize;
+        let mut pos = 0;
+
+        while pos < bytes.len() {
+            let prefix = bytes[pos];
+            if prefix == ITEM_LONG {
+                // Long item: prefix, data size, long item tag, data
+                let Some(data_len) = bytes.get(pos + 1) else {
+                    bail!("Truncated long item at offset {}", pos);
+                };
+                pos += 3 + *data_len as usize;
+                continue;
+            }
+
+            let data_len = match prefix & 0x03 {
+                3 => 4,
+                size => size as usize,
+            };
+            let Some(data) = bytes.get(pos + 1..pos + 1 + data_len) else {
+                bail!("Truncated item 0x{:02x} at offset {}", prefix, pos);
+            };
+            let value = data.iter().rev().fold(0u32, |acc, byte| (acc << 8) | *byte as u32);
+
+            match ((prefix >> 2) & 0x03, prefix >> 4) {
+                (TYPE_GLOBAL, GLOBAL_USAGE_PAGE) => global.usage_page = value as u16,
+                (TYPE_GLOBAL, GLOBAL_REPORT_ID) => {
+                    let report_id = value as u8;
+                    global.report_id = Some(report_id);
+                    if !descriptor.report_ids.contains(&report_id) {
+                        descriptor.report_ids.push(report_id);
+                    }
+                    if depth > 0 {
+                        descriptor.add_to_collection(report_id);
+                    }
+                }
+                (TYPE_GLOBAL, GLOBAL_PUSH) => global_stack.push(global),
+                (TYPE_GLOBAL, GLOBAL_POP) => match global_stack.pop() {
+                    Some(state) => global = state,
+                    None => bail!("Pop without push at offset {}", pos),
+                },
+                // Four byte usages carry their own usage page in the high half
+                (TYPE_LOCAL, LOCAL_USAGE) if data_len == 4 => {
+                    usage = Some((Some((value >> 16) as u16), value as u16));
+                }
+                (TYPE_LOCAL, LOCAL_USAGE) => {
+                    // Only the first usage names a collection
+                    usage.get_or_insert((None, value as u16));
+                }
+                (TYPE_MAIN, tag) => {
+                    if tag == MAIN_COLLECTION {
+                        if depth == 0 {
+                            let (usage_page, usage) = usage.unwrap_or_default();
+                            descriptor.collections.push(Collection {
+                                usage_page: usage_page.unwrap_or(global.usage_page),
+                                usage,
+                                report_ids: Vec::new(),
+                            });
+                        }
+                        depth += 1;
+                    } else if tag == MAIN_END_COLLECTION {
+                        if depth == 0 {
+                            bail!("End Collection without Collection at offset {}", pos);
+                        }
+                        depth -= 1;
+                    } else if matches!(tag, MAIN_INPUT | MAIN_OUTPUT | MAIN_FEATURE) && depth > 0 {
+                        // Report IDs are global, a collection may reuse the
+                        // one declared before it
+                        if let Some(report_id) = global.report_id {
+                            descriptor.add_to_collection(report_id);
+                        }
+                    }
+                    // Local items only apply up to the next main item
+                    usage = None;
+                }
+                _ => {}
+            }
+
+            pos += 1 + data_len;
+        }
+
+        Ok(descriptor)
+    }
+
+    fn add_to_collection(&mut self, report_id: u8) {
+        if let Some(collection) = self.collections.last_mut() {
+            if !collection.report_ids.contains(&report_id) {
+                collection.report_ids.push(report_id);
+            }
+        }
+    }
+
+    /// HID++ report types of the interface, `None` when no top-level
+    /// collection is on a HID++ vendor usage page with HID++ report IDs.
+    ///
+    /// Receivers declare short and long reports in separate collections.
+    pub fn hidpp_reports(&self) -> Option<SupportedReports> {
+        let report_ids: Vec<u8> = self.collections
+            .iter()
+            .filter(|collection| collection.is_hidpp())
+            .flat_map(|collection| collection.report_ids.iter().copied())
+            .collect();
+
+        (!report_ids.is_empty()).then(|| SupportedReports::from_report_ids(&report_ids))
+    }
+}
+
+impl Collection {
+    pub fn is_hidpp(&self) -> bool {
+        HIDPP_USAGE_PAGES.contains(&self.usage_page)
+            && self.report_ids.iter().any(|id| ReportType::from_id(*id).is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Interface 2 of a Unifying receiver: short and long HID++ reports in
+    // two collections, followed by the DJ reports
+    const RECEIVER_DESCRIPTOR: [u8; 65] = [
+        0x06, 0x00, 0xFF, // Usage Page (Vendor 0xFF00)
+        0x09, 0x01,       // Usage (0x01)
+        0xA1, 0x01,       // Collection (Application)
+        0x85, 0x10,       //   Report ID (0x10)
+        0x75, 0x08,       //   Report Size (8)
+        0x95, 0x06,       //   Report Count (6)
+        0x15, 0x00,       //   Logical Minimum (0)
+        0x26, 0xFF, 0x00, //   Logical Maximum (255)
+        0x09, 0x01,       //   Usage (0x01)
+        0x81, 0x00,       //   Input
+        0x09, 0x01,       //   Usage (0x01)
+        0x91, 0x00,       //   Output
+        0xC0,             // End Collection
+        0x06, 0x00, 0xFF, // Usage Page (Vendor 0xFF00)
+        0x09, 0x02,       // Usage (0x02)
+        0xA1, 0x01,       // Collection (Application)
+        0x85, 0x11,       //   Report ID (0x11)
+        0x75, 0x08,       //   Report Size (8)
+        0x95, 0x13,       //   Report Count (19)
+        0x09, 0x02,       //   Usage (0x02)
+        0x81, 0x00,       //   Input
+        0x09, 0x02,       //   Usage (0x02)
+        0x91, 0x00,       //   Output
+        0xC0,             // End Collection
+        0x06, 0x00, 0xFF, // Usage Page (Vendor 0xFF00)
+        0x09, 0x04,       // Usage (0x04)
+        0xA1, 0x01,       // Collection (Application)
+        0x85, 0x20,       //   Report ID (0x20)
+        0x95, 0x0E,       //   Report Count (14)
+        0x09, 0x41,       //   Usage (0x41)
+        0x81, 0x00,       //   Input
+        0xC0,             // End Collection
+    ];
+
+    // Boot keyboard interface: no report IDs at all
+    const KEYBOARD_DESCRIPTOR: [u8; 23] = [
+        0x05, 0x01,       // Usage Page (Generic Desktop)
+        0x09, 0x06,       // Usage (Keyboard)
+        0xA1, 0x01,       // Collection (Application)
+        0x05, 0x07,       //   Usage Page (Keyboard)
+        0x19, 0xE0,       //   Usage Minimum (0xE0)
+        0x29, 0xE7,       //   Usage Maximum (0xE7)
+        0x75, 0x01,       //   Report Size (1)
+        0x95, 0x08,       //   Report Count (8)
+        0x81, 0x02,       //   Input (Data, Variable)
+        0xA1, 0x00,       //   Collection (Physical)
+        0xC0,             //   End Collection
+        0xC0,             // End Collection
+        0x00,
+    ];
+
+    #[test]
+    fn test_parse_receiver_descriptor() {
+        let descriptor = ReportDescriptor::parse(&RECEIVER_DESCRIPTOR).unwrap();
+        assert_eq!(descriptor.collections, vec![
+            Collection { usage_page: 0xFF00, usage: 0x01, report_ids: vec![0x10] },
+            Collection { usage_page: 0xFF00, usage: 0x02, report_ids: vec![0x11] },
+            Collection { usage_page: 0xFF00, usage: 0x04, report_ids: vec![0x20] },
+        ]);
+        assert_eq!(descriptor.report_ids, vec![0x10, 0x11, 0x20]);
+
+        assert_eq!(descriptor.hidpp_reports(), Some(SupportedReports { short: true, long: true, very_long: false }));
+    }
+
+    #[test]
+    fn test_keyboard_interface_is_not_hidpp() {
+        let descriptor = ReportDescriptor::parse(&KEYBOARD_DESCRIPTOR).unwrap();
+        assert_eq!(descriptor.collections, vec![
+            Collection { usage_page: 0x0001, usage: 0x06, report_ids: vec![] },
+        ]);
+        assert_eq!(descriptor.hidpp_reports(), None);
+    }
+
+    #[test]
+    fn test_hidpp_report_ids_need_vendor_usage_page() {
+        // Report ID 0x11 in a consumer control collection
+        let descriptor = [0x05, 0x0C, 0x09, 0x01, 0xA1, 0x01, 0x85, 0x11, 0xC0];
+        let descriptor = ReportDescriptor::parse(&descriptor).unwrap();
+        assert_eq!(descriptor.hidpp_reports(), None);
+    }
+
+    #[test]
+    fn test_extended_usage_and_push_pop() {
+        let descriptor = [
+            0x0B, 0x02, 0x06, 0x43, 0xFF, // Usage (0xFF43:0x0602)
+            0xA1, 0x01,                   // Collection (Application)
+            0xA4,                         //   Push
+            0x85, 0x12,                   //   Report ID (0x12)
+            0xB4,                         //   Pop
+            0xC0,                         // End Collection
+        ];
+        let descriptor = ReportDescriptor::parse(&descriptor).unwrap();
+        assert_eq!((descriptor.collections[0].usage_page, descriptor.collections[0].usage), (0xFF43, 0x0602));
+        assert_eq!(descriptor.hidpp_reports(), Some(SupportedReports { short: false, long: false, very_long: true }));
+    }
+
+    #[test]
+    fn test_top_level_collections() {
+        // HID++ collection with a two byte usage page
+        let hidpp = [0x06, 0x00, 0xFF, 0x09, 0x01, 0xA1, 0x01, 0x85, 0x10, 0xC0];
+        let collections = ReportDescriptor::parse(&hidpp).unwrap().collections;
+        assert_eq!((collections[0].usage_page, collections[0].usage), (0xFF00, 0x0001));
+
+        // Extended usage
+        let extended = [0x0B, 0x02, 0x00, 0x43, 0xFF, 0xA1, 0x01, 0xC0];
+        let collections = ReportDescriptor::parse(&extended).unwrap().collections;
+        assert_eq!((collections[0].usage_page, collections[0].usage), (0xFF43, 0x0002));
+    }
+
+    #[test]
+    fn test_malformed_descriptors() {
+        assert!(ReportDescriptor::parse(&[0x06, 0x00]).is_err());
+        assert!(ReportDescriptor::parse(&[0xC0]).is_err());
+        assert!(ReportDescriptor::parse(&[0xB4]).is_err());
+    }
+}
@@ -1,84 +1,1484 @@
-use anyhow::{Context, Result};
-use hidapi::{HidApi, HidDevice};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use anyhow::{bail, Context, Result};
 use log::{debug, warn};
+use tokio::sync::mpsc;
+
+use crate::config::{HidBackend, HidSelector, VoltagePoint};
+use super::listener::{self, DeviceEvent, NotificationDecoder};
+use super::hidraw;
+use super::transport::{self, BusType, HidConnection, HidInterfaceInfo, HidTransport};
+use super::descriptor::ReportDescriptor;
+use super::source::BatterySource;
+use super::hidpp::{HidppError, HidppMessage, Hidpp10Error, ReplyMatch, ReportType, SupportedReports, HIDPP_USAGE_PAGES, LONG_REPORT_LEN, MAX_SOFTWARE_ID, VERY_LONG_REPORT_LEN};
+
+pub const LOGITECH_VENDOR_ID: u16 = 0x046D;
+pub const UNIFYING_RECEIVER_PRODUCT_IDS: [u16; 2] = [0xC52B, 0xC532];
+pub const BOLT_RECEIVER_PRODUCT_IDS: [u16; 1] = [0xC548];
+
+// Device index addressing a device connected directly over USB or Bluetooth,
+// or the receiver itself
+const DIRECT_DEVICE_INDEX: u8 = 0xFF;
+const RECEIVER_DEVICE_INDEX: u8 = 0xFF;
+const MAX_RECEIVER_SLOTS: u8 = 6;
+const READ_TIMEOUT_MS: u32 = 1000;
+
+// HID++ 2.0 feature IDs
+pub const FEATURE_ROOT: u16 = 0x0000;
+pub const FEATURE_FEATURE_SET: u16 = 0x0001;
+pub const FEATURE_DEVICE_INFORMATION: u16 = 0x0003;
+pub const FEATURE_DEVICE_NAME_TYPE: u16 = 0x0005;
+pub const FEATURE_BATTERY_STATUS: u16 = 0x1000;
+pub const FEATURE_BATTERY_VOLTAGE: u16 = 0x1001;
+pub const FEATURE_UNIFIED_BATTERY: u16 = 0x1004;
+
+// Battery features in order of preference
+const BATTERY_FEATURES: [u16; 3] = [FEATURE_UNIFIED_BATTERY, FEATURE_BATTERY_STATUS, FEATURE_BATTERY_VOLTAGE];
+
+// IRoot (0x0000) always lives at feature index 0
+const ROOT_FEATURE_INDEX: u8 = 0x00;
+const ROOT_GET_FEATURE: u8 = 0x00;
+
+const FEATURE_SET_GET_COUNT: u8 = 0x00;
+const FEATURE_SET_GET_FEATURE_ID: u8 = 0x01;
+
+const DEVICE_INFORMATION_GET_DEVICE_INFO: u8 = 0x00;
+const DEVICE_INFORMATION_GET_FW_INFO: u8 = 0x01;
+const DEVICE_INFORMATION_GET_SERIAL_NUMBER: u8 = 0x02;
+// Capability bit of getDeviceInfo
+const DEVICE_INFORMATION_SERIAL_NUMBER: u8 = 1 << 0;
+
+const DEVICE_NAME_GET_COUNT: u8 = 0x00;
+const DEVICE_NAME_GET_NAME: u8 = 0x01;
+const DEVICE_NAME_GET_TYPE: u8 = 0x02;
+
+// HID++ 1.0 register access
+const GET_LONG_REGISTER: u8 = 0x83;
+const REGISTER_RECEIVER_INFO: u8 = 0xB5;
+// Receiver info sub-registers, offset by the pairing slot
+const UNIFYING_PAIRING_INFO: u8 = 0x20;
+const UNIFYING_DEVICE_NAME: u8 = 0x40;
+const BOLT_PAIRING_INFO: u8 = 0x50;
+const BOLT_DEVICE_NAME: u8 = 0x60;
+
+const BATTERY_STATUS_GET_LEVEL_STATUS: u8 = 0x00;
+const BATTERY_VOLTAGE_GET_VOLTAGE: u8 = 0x00;
+
+// BatteryVoltage flags
+const VOLTAGE_FLAG_CHARGE_STATUS_MASK: u8 = 0x03;
+const VOLTAGE_FLAG_SLOW_CHARGE: u8 = 1 << 4;
+const VOLTAGE_FLAG_CRITICAL: u8 = 1 << 5;
+const VOLTAGE_FLAG_EXTERNAL_POWER: u8 = 1 << 7;
+
+// Discharge curve of a single Li-ion cell, as used by Solaar
+const LI_ION_VOLTAGE_CURVE: [(u16, u8); 13] = [
+    (4186, 100), (4067, 90), (3989, 80), (3922, 70), (3859, 60), (3811, 50), (3778, 40),
+    (3751, 30), (3717, 20), (3671, 10), (3646, 5), (3579, 2), (3500, 0),
+];
+
+const UNIFIED_BATTERY_GET_CAPABILITIES: u8 = 0x00;
+const UNIFIED_BATTERY_GET_STATUS: u8 = 0x01;
+
+// UnifiedBattery level flags, shared by get_capabilities and get_status
+const LEVEL_CRITICAL: u8 = 1 << 0;
+const LEVEL_LOW: u8 = 1 << 1;
+const LEVEL_GOOD: u8 = 1 << 2;
+const LEVEL_FULL: u8 = 1 << 3;
+
+// UnifiedBattery capability flags
+const CAPABILITY_RECHARGEABLE: u8 = 1 << 0;
+const CAPABILITY_STATE_OF_CHARGE: u8 = 1 << 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatteryLevel {
+    Critical,
+    Low,
+    Good,
+    Full,
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChargingStatus {
+    Discharging,
+    Charging,
+    SlowCharging,
+    Full,
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnifiedBatteryCapabilities {
+    pub supported_levels: u8,
+    pub rechargeable: bool,
+    pub state_of_charge: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatteryStatus {
+    // Only reported by devices advertising state-of-charge support
+    pub percentage: Option<u8>,
+    pub level: BatteryLevel,
+    pub charging: ChargingStatus,
+    pub external_power: bool,
+    // Only reported by BatteryVoltage (0x1001) devices
+    pub voltage_mv: Option<u16>,
+}
+
+/// Voltage-to-percentage mapping for BatteryVoltage (0x1001) devices,
+/// linearly interpolated between points.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoltageCurve {
+    // Sorted by descending voltage
+    points: Vec<VoltagePoint>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReceiverKind {
+    Unifying,
+    Bolt,
+}
+
+/// How the device battery is read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceTransport {
+    Usb,
+    Bluetooth,
+    Receiver(ReceiverKind),
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    Keyboard,
+    Mouse,
+    Numpad,
+    Presenter,
+    Remote,
+    Trackball,
+    Touchpad,
+    Other(u8),
+}
+
+/// A device paired to a Unifying or Bolt receiver.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairedDevice {
+    // Pairing slot, doubles as the HID++ device index
+    pub slot: u8,
+    pub wireless_product_id: u16,
+    pub kind: DeviceKind,
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirmwareKind {
+    Firmware,
+    Bootloader,
+    Hardware,
+    Other(u8),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirmwareVersion {
+    pub kind: FirmwareKind,
+    // Three letter prefix such as "MPK", empty for hardware revisions
+    pub name: String,
+    pub version: String,
+}
+
+/// A HID++ 2.0 feature as listed by FeatureSet (0x0001).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeatureEntry {
+    pub id: u16,
+    pub index: u8,
+    pub version: u8,
+}
+
+/// Identity of a HID++ 2.0 device, read from DeviceNameType (0x0005),
+/// DeviceInformation (0x0003) and FeatureSet (0x0001).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub name: Option<String>,
+    pub kind: Option<DeviceKind>,
+    pub unit_id: Option<String>,
+    pub serial_number: Option<String>,
+    pub model_ids: Vec<u16>,
+    // Bit 0 Bluetooth, bit 1 BLE, bit 2 Unifying/eQuad, bit 3 USB
+    pub transports: u16,
+    pub firmware: Vec<FirmwareVersion>,
+    pub features: Vec<FeatureEntry>,
+}
+
+/// An opened HID++ interface and the report types it was found to accept.
+pub struct HidppDevice {
+    device: Box<dyn HidConnection>,
+    path: String,
+    reports: SupportedReports,
+    transport: DeviceTransport,
+    // Last software ID stamped into a request
+    software_id: Cell<u8>,
+}
 
 pub struct LogitechManager {
-    api: HidApi,
-    hid_device: Option<HidDevice>
+    transport: Box<dyn HidTransport>,
+    // Handle kept open across polls, with the device index to address on it
+    hid_device: Option<(HidppDevice, u8)>,
+    selector: HidSelector,
+    // Product IDs the device enumerates with when connected over Bluetooth
+    bluetooth_product_ids: Vec<u16>,
+    // Canonical sysfs path of the USB device the HID interface must sit on
+    usb_device: Option<PathBuf>,
+    // Feature ID -> feature index, per (hidraw path, device index).
+    // `None` records that the device does not support the feature.
+    feature_cache: HashMap<(String, u8), HashMap<u16, Option<u8>>>,
+    voltage_curve: VoltageCurve,
 }
 
 impl LogitechManager {
-    pub fn new() -> Result<Self> {
-        let api = HidApi::new()
-            .context("Failed to initialize HID API")?;
+    pub fn new(backend: HidBackend) -> Result<Self> {
+        Ok(Self::with_transport(transport::open_transport(backend)?))
+    }
 
-        Ok(Self { api, hid_device: None })
+    pub fn with_transport(transport: Box<dyn HidTransport>) -> Self {
+        Self {
+            transport,
+            hid_device: None,
+            selector: HidSelector::default(),
+            bluetooth_product_ids: Vec::new(),
+            usb_device: None,
+            feature_cache: HashMap::new(),
+            voltage_curve: VoltageCurve::default(),
+        }
     }
 
-    pub fn select_hid_device(name_selector: &String) -> Result<HidDevice, String> {
-        Err("No device selected".to_string())
+    pub fn set_voltage_curve(&mut self, voltage_curve: VoltageCurve) {
+        self.voltage_curve = voltage_curve;
     }
 
+    pub fn set_selector(&mut self, selector: HidSelector) {
+        self.selector = selector;
+        self.hid_device = None;
+    }
 
-pub fn get_battery_level(&self, vendor_id: u16, product_id: u16) -> Result<Option<u8>> {
-        let device_info = self.api
-            .device_list()
-            .find(|dev| dev.vendor_id() == vendor_id && dev.product_id() == product_id);
-        
-        if let Some(info) = device_info {
-            let device = info.open_device(&self.api)
-                .context("Failed to open HID device")?;
-            
-            self.read_battery_from_device(&device)
-        } else {
-            debug!("HID device not found: {:04x}:{:04x}", vendor_id, product_id);
-            Ok(None)
-        }
-    }
-    
-    fn read_battery_from_device(&self, device: &HidDevice) -> Result<Option<u8>> {
-        // Logitech HID++ protocol for battery status
-        // This is a simplified implementation - actual protocol may vary
-        let mut buf = [0u8; 20];
-        
-        // HID++ short message: device_index=0xFF, feature_index=0x00, function=0x00
-        // This requests basic device information
-        buf[0] = 0x10; // Report ID for HID++
-        buf[1] = 0xFF; // Device index (unifying receiver)
-        buf[2] = 0x00; // Feature index
-        buf[3] = 0x00; // Function
-        
-        match device.write(&buf[..4]) {
-            Ok(_) => {
-                // Read response
-                match device.read_timeout(&mut buf, 1000) {
-                    Ok(bytes_read) if bytes_read > 0 => {
-                        // Parse battery level from response
-                        // This is device-specific and may need adjustment
-                        if buf[0] == 0x10 && bytes_read >= 7 {
-                            let battery_level = buf[6]; // Battery percentage
-                            debug!("Battery level read: {}%", battery_level);
-                            Ok(Some(battery_level))
-                        } else {
-                            warn!("Unexpected HID response format");
-                            Ok(None)
-                        }
-                    }
-                    Ok(_) => {
-                        warn!("Empty HID response");
-                        Ok(None)
-                    }
-                    Err(e) => {
-                        warn!("Failed to read from HID device: {}", e);
-                        Ok(None)
-                    }
+    /// Also accept Bluetooth HID interfaces enumerating with one of
+    /// `product_ids`, next to the USB product ID passed to the lookups.
+    pub fn set_bluetooth_product_ids(&mut self, product_ids: Vec<u16>) {
+        self.bluetooth_product_ids = product_ids;
+        self.hid_device = None;
+    }
+
+    /// How the currently selected device is reached, if any.
+    pub fn selected_transport(&self) -> Option<DeviceTransport> {
+        self.hid_device.as_ref().map(|(device, _)| device.transport)
+    }
+
+    /// Only consider HID interfaces of the USB device at `sys_path`, which
+    /// tells identical devices apart when several are plugged in.
+    pub fn set_usb_device(&mut self, sys_path: &str) {
+        let usb_device = fs::canonicalize(sys_path).ok();
+        if usb_device != self.usb_device {
+            self.usb_device = usb_device;
+            self.hid_device = None;
+        }
+    }
+
+    /// Open the HID++ interface of the device matching `vendor_id`/`product_id`
+    /// and the configured selector, and keep it for later polls.
+    ///
+    /// Only interfaces whose report descriptor declares a HID++ collection are
+    /// considered, so the keyboard/mouse interfaces of the same device are
+    /// skipped. USB interfaces are preferred over Bluetooth ones, and devices
+    /// paired to a receiver are looked up when no interface matches directly.
+    pub fn select_hid_device(&mut self, vendor_id: u16, product_id: u16) -> Result<()> {
+        let mut candidates: Vec<HidInterfaceInfo> = self.transport
+            .devices()?
+            .into_iter()
+            .filter(|dev| dev.vendor_id == vendor_id && self.matches_product(dev, product_id))
+            .filter(|dev| matches_selector(dev, &self.selector))
+            .filter(|dev| self.is_on_usb_device(dev))
+            .collect();
+        // Probe the likely HID++ interfaces first, and each hidraw node once
+        // even when hidapi lists it per top-level collection
+        candidates.sort_by_key(|dev| (dev.bus_type == BusType::Bluetooth, !HIDPP_USAGE_PAGES.contains(&dev.usage_page)));
+        let mut probed = Vec::new();
+
+        for info in candidates {
+            if probed.contains(&info.path) {
+                continue;
+            }
+            probed.push(info.path.clone());
+
+            match self.open_hidpp_interface(&info) {
+                Ok(Some(device)) => {
+                    debug!("Selected HID device {} over {} (interface {}, usage page 0x{:04x})",
+                           device.path, device.transport, info.interface_number, info.usage_page);
+                    self.hid_device = Some((device, DIRECT_DEVICE_INDEX));
+                    return Ok(());
                 }
+                Ok(None) => debug!("{} has no HID++ collection", info.path),
+                Err(e) => warn!("Failed to open {}: {}", info.path, e),
+            }
+        }
+
+        match self.find_paired_device(vendor_id, product_id)? {
+            Some((receiver, paired)) => {
+                debug!("Selected device {:04x}:{:04x} paired to receiver {} in slot {}",
+                       vendor_id, product_id, receiver.path, paired.slot);
+                self.hid_device = Some((receiver, paired.slot));
+                Ok(())
+            }
+            None => bail!("No HID device matches {:04x}:{:04x} and {:?}", vendor_id, product_id, self.selector),
+        }
+    }
+
+    /// Open the interface at `info` if its report descriptor declares HID++
+    /// reports on a vendor usage page.
+    ///
+    /// # Returns
+    /// * `Ok(Some(device))` - HID++ interface, ready for requests
+    /// * `Ok(None)` - Some other interface of the device, e.g. the keyboard one
+    fn open_hidpp_interface(&self, info: &HidInterfaceInfo) -> Result<Option<HidppDevice>> {
+        let connection = self.transport.open(&info.path)?;
+
+        let reports = match connection.report_descriptor().and_then(|bytes| ReportDescriptor::parse(&bytes)) {
+            Ok(descriptor) => match descriptor.hidpp_reports() {
+                Some(reports) => reports,
+                None => return Ok(None),
+            },
+            // Without a descriptor, trust the usage page from enumeration
+            Err(e) if HIDPP_USAGE_PAGES.contains(&info.usage_page) => {
+                debug!("Failed to parse report descriptor of {}, assuming short and long reports: {}", info.path, e);
+                SupportedReports::default()
             }
             Err(e) => {
-                warn!("Failed to write to HID device: {}", e);
-                Ok(None)
+                debug!("Failed to parse report descriptor of {}: {}", info.path, e);
+                return Ok(None);
+            }
+        };
+        debug!("HID++ reports supported by {}: {:?}", info.path, reports);
+
+        let transport = match info.bus_type {
+            BusType::Usb => DeviceTransport::Usb,
+            BusType::Bluetooth => DeviceTransport::Bluetooth,
+            BusType::Unknown | BusType::Other => DeviceTransport::Unknown,
+        };
+        Ok(Some(HidppDevice::new(connection, info.path.clone(), reports, transport)))
+    }
+
+    fn matches_product(&self, info: &HidInterfaceInfo, product_id: u16) -> bool {
+        info.product_id == product_id
+            || (info.bus_type == BusType::Bluetooth && self.bluetooth_product_ids.contains(&info.product_id))
+    }
+
+    fn is_on_usb_device(&self, info: &HidInterfaceInfo) -> bool {
+        // Interfaces that cannot be traced back to a USB device are kept
+        match (&self.usb_device, hidraw::usb_device_path(&info.path)) {
+            (Some(usb_device), Some(path)) => *usb_device == path,
+            _ => true,
+        }
+    }
+
+    pub fn get_battery_level(&mut self, vendor_id: u16, product_id: u16) -> Result<Option<BatteryStatus>> {
+        let Some((device, device_index)) = self.take_selected_device(vendor_id, product_id) else {
+            return Ok(None);
+        };
+        let status = self.read_battery_from_device(&device, device_index)?;
+
+        if status.is_some() {
+            self.hid_device = Some((device, device_index));
+        } else {
+            // Most likely unplugged or reconnected, start over on the next poll
+            debug!("Closing HID device {}", device.path);
+            self.feature_cache.retain(|(path, _), _| *path != device.path);
+        }
+        Ok(status)
+    }
+
+    /// Start a background task listening for battery and connection
+    /// notifications of the selected device.
+    ///
+    /// The task keeps its own handle on the hidraw node and ends once the
+    /// returned receiver is dropped or the device goes away.
+    pub fn start_listener(&mut self, vendor_id: u16, product_id: u16) -> Result<Option<mpsc::Receiver<DeviceEvent>>> {
+        let Some((device, device_index)) = self.take_selected_device(vendor_id, product_id) else {
+            return Ok(None);
+        };
+        let decoder = self.notification_decoder(&device, device_index);
+        let path = device.path.clone();
+        self.hid_device = Some((device, device_index));
+        let decoder = decoder?;
+
+        let listener_device = self.transport.open(&path)
+            .context("Failed to open HID device for notifications")?;
+
+        debug!("Listening for HID++ notifications on {}", path);
+        Ok(Some(listener::spawn(listener_device, path, decoder)))
+    }
+
+    fn notification_decoder(&mut self, device: &HidppDevice, device_index: u8) -> Result<NotificationDecoder> {
+        let mut decoder = NotificationDecoder::new(self.voltage_curve.clone());
+
+        for feature_id in BATTERY_FEATURES {
+            if let Some(feature_index) = self.get_feature_index(device, device_index, feature_id)? {
+                let capabilities = if feature_id == FEATURE_UNIFIED_BATTERY {
+                    let params = self.request(device, device_index, feature_index, UNIFIED_BATTERY_GET_CAPABILITIES, &[])?;
+                    Some(UnifiedBatteryCapabilities::from_params(&params))
+                } else {
+                    None
+                };
+                decoder.add_battery_feature(device_index, feature_index, feature_id, capabilities);
+                break;
+            }
+        }
+
+        Ok(decoder)
+    }
+
+    /// Read name, model, firmware and feature list of the selected device.
+    ///
+    /// Returns `Ok(None)` when no device is found. Features missing on the
+    /// device leave the corresponding fields empty.
+    pub fn get_device_info(&mut self, vendor_id: u16, product_id: u16) -> Result<Option<DeviceInfo>> {
+        let Some((device, device_index)) = self.take_selected_device(vendor_id, product_id) else {
+            return Ok(None);
+        };
+        let info = self.read_device_info(&device, device_index);
+        self.hid_device = Some((device, device_index));
+        info.map(Some)
+    }
+
+    fn take_selected_device(&mut self, vendor_id: u16, product_id: u16) -> Option<(HidppDevice, u8)> {
+        if self.hid_device.is_none() {
+            if let Err(e) = self.select_hid_device(vendor_id, product_id) {
+                debug!("HID device not found: {}", e);
+                return None;
+            }
+        }
+        self.hid_device.take()
+    }
+
+    fn read_device_info(&mut self, device: &HidppDevice, device_index: u8) -> Result<DeviceInfo> {
+        let mut info = DeviceInfo {
+            features: self.read_feature_set(device, device_index)?,
+            ..DeviceInfo::default()
+        };
+
+        if let Some(index) = self.get_feature_index(device, device_index, FEATURE_DEVICE_NAME_TYPE)? {
+            let count = self.request(device, device_index, index, DEVICE_NAME_GET_COUNT, &[])?[0] as usize;
+            let mut name = Vec::with_capacity(count);
+            while name.len() < count {
+                let chunk = self.request(device, device_index, index, DEVICE_NAME_GET_NAME, &[name.len() as u8])?;
+                let take = (count - name.len()).min(chunk.len());
+                name.extend_from_slice(&chunk[..take]);
+            }
+            info.name = Some(String::from_utf8_lossy(&name).trim_end_matches('\0').to_string());
+
+            let kind = self.request(device, device_index, index, DEVICE_NAME_GET_TYPE, &[])?[0];
+            info.kind = Some(DeviceKind::from_name_type(kind));
+        }
+
+        if let Some(index) = self.get_feature_index(device, device_index, FEATURE_DEVICE_INFORMATION)? {
+            let params = self.request(device, device_index, index, DEVICE_INFORMATION_GET_DEVICE_INFO, &[])?;
+            let entity_count = params[0];
+            info.unit_id = Some(format!("{:02X}{:02X}{:02X}{:02X}", params[1], params[2], params[3], params[4]));
+            info.transports = u16::from_be_bytes([params[5], params[6]]);
+            info.model_ids = params[7..13]
+                .chunks(2)
+                .map(|id| u16::from_be_bytes([id[0], id[1]]))
+                .filter(|id| *id != 0)
+                .collect();
+
+            for entity in 0..entity_count {
+                let params = self.request(device, device_index, index, DEVICE_INFORMATION_GET_FW_INFO, &[entity])?;
+                info.firmware.push(FirmwareVersion::from_params(&params));
+            }
+
+            if params[14] & DEVICE_INFORMATION_SERIAL_NUMBER != 0 {
+                let serial = self.request(device, device_index, index, DEVICE_INFORMATION_GET_SERIAL_NUMBER, &[])?;
+                info.serial_number = Some(String::from_utf8_lossy(&serial[..12]).trim_end_matches('\0').to_string());
+            }
+        }
+
+        Ok(info)
+    }
+
+    /// List every feature of the device and seed the feature index cache with it.
+    fn read_feature_set(&mut self, device: &HidppDevice, device_index: u8) -> Result<Vec<FeatureEntry>> {
+        let Some(feature_set_index) = self.get_feature_index(device, device_index, FEATURE_FEATURE_SET)? else {
+            return Ok(Vec::new());
+        };
+
+        // The count excludes IRoot at index 0
+        let count = self.request(device, device_index, feature_set_index, FEATURE_SET_GET_COUNT, &[])?[0];
+        let mut features = Vec::with_capacity(count as usize + 1);
+        for index in 0..=count {
+            let params = self.request(device, device_index, feature_set_index, FEATURE_SET_GET_FEATURE_ID, &[index])?;
+            features.push(FeatureEntry {
+                id: u16::from_be_bytes([params[0], params[1]]),
+                index,
+                version: params[3],
+            });
+        }
+
+        let cache = self.feature_cache.entry((device.path.clone(), device_index)).or_default();
+        for feature in &features {
+            cache.insert(feature.id, Some(feature.index));
+        }
+        Ok(features)
+    }
+
+    /// Look for a device behind any attached Unifying or Bolt receiver,
+    /// matching `product_id` against the wireless product ID of each slot.
+    pub fn find_paired_device(&mut self, vendor_id: u16, product_id: u16) -> Result<Option<(HidppDevice, PairedDevice)>> {
+        if vendor_id != LOGITECH_VENDOR_ID {
+            return Ok(None);
+        }
+
+        let mut receivers: Vec<_> = self.transport
+            .devices()?
+            .into_iter()
+            .filter(|dev| dev.vendor_id == LOGITECH_VENDOR_ID)
+            .filter_map(|dev| receiver_kind(dev.product_id).map(|kind| (dev, kind)))
+            .collect();
+        receivers.dedup_by(|(a, _), (b, _)| a.path == b.path);
+
+        for (info, kind) in receivers {
+            let device = match self.open_hidpp_interface(&info) {
+                Ok(Some(device)) => device,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Failed to open receiver {}: {}", info.path, e);
+                    continue;
+                }
+            };
+
+            let paired = match self.paired_devices(&device, kind) {
+                Ok(paired) => paired,
+                Err(e) => {
+                    warn!("Failed to list devices paired with receiver {}: {:#}", info.path, e);
+                    continue;
+                }
+            };
+            if let Some(paired) = paired.into_iter().find(|p| p.wireless_product_id == product_id) {
+                let device = HidppDevice { transport: DeviceTransport::Receiver(kind), ..device };
+                return Ok(Some((device, paired)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Enumerate pairing slots 1-6 of a receiver.
+    pub fn paired_devices(&self, receiver: &HidppDevice, kind: ReceiverKind) -> Result<Vec<PairedDevice>> {
+        let mut paired = Vec::new();
+
+        for slot in 1..=MAX_RECEIVER_SLOTS {
+            let (info_register, name_register) = match kind {
+                ReceiverKind::Unifying => (UNIFYING_PAIRING_INFO + slot - 1, UNIFYING_DEVICE_NAME + slot - 1),
+                ReceiverKind::Bolt => (BOLT_PAIRING_INFO + slot, BOLT_DEVICE_NAME + slot),
+            };
+
+            let info = match self.read_long_register(receiver, REGISTER_RECEIVER_INFO, &[info_register]) {
+                Ok(info) => info,
+                // Empty slots are rejected by the receiver
+                Err(e) if is_empty_slot_error(&e) => continue,
+                Err(e) => return Err(e.context(format!("Failed to read pairing info of slot {}", slot))),
+            };
+
+            let name_params: &[u8] = match kind {
+                ReceiverKind::Unifying => &[name_register],
+                // Bolt names come in parts, the first one is enough for logs
+                ReceiverKind::Bolt => &[name_register, 0x01],
+            };
+            let name = match self.read_long_register(receiver, REGISTER_RECEIVER_INFO, name_params) {
+                Ok(data) => parse_device_name(kind, &data),
+                Err(e) => {
+                    debug!("Failed to read name of slot {}: {}", slot, e);
+                    None
+                }
+            };
+
+            let device = match PairedDevice::from_pairing_info(kind, slot, &info, name) {
+                Ok(device) => device,
+                Err(e) => {
+                    warn!("Skipping slot {} of receiver {}: {}", slot, receiver.path, e);
+                    continue;
+                }
+            };
+            debug!("Receiver {} slot {}: {:?}", receiver.path, slot, device);
+            paired.push(device);
+        }
+
+        Ok(paired)
+    }
+
+    /// Resolve a HID++ 2.0 feature ID to its feature index on the device
+    /// using IRoot `getFeature`.
+    ///
+    /// Results are cached per device, so only the first lookup of a feature
+    /// goes to the wire.
+    ///
+    /// # Returns
+    /// * `Ok(Some(index))` - Feature index to address the feature with
+    /// * `Ok(None)` - The device does not support the feature
+    pub fn get_feature_index(&mut self, device: &HidppDevice, device_index: u8, feature_id: u16) -> Result<Option<u8>> {
+        let cache_key = (device.path.clone(), device_index);
+        if let Some(index) = self.feature_cache.get(&cache_key).and_then(|f| f.get(&feature_id)) {
+            return Ok(*index);
+        }
+
+        let index = if feature_id == FEATURE_ROOT {
+            Some(ROOT_FEATURE_INDEX)
+        } else {
+            let [id_hi, id_lo] = feature_id.to_be_bytes();
+            let params = self.request(device, device_index, ROOT_FEATURE_INDEX, ROOT_GET_FEATURE, &[id_hi, id_lo])?;
+            // Index 0 is IRoot itself, so it doubles as "not supported"
+            match params[0] {
+                0 => None,
+                index => Some(index),
+            }
+        };
+
+        debug!("Feature 0x{:04x} on {} (device index 0x{:02x}): {:?}",
+               feature_id, device.path, device_index, index);
+        self.feature_cache.entry(cache_key).or_default().insert(feature_id, index);
+        Ok(index)
+    }
+
+    fn read_battery_from_device(&mut self, device: &HidppDevice, device_index: u8) -> Result<Option<BatteryStatus>> {
+        for feature_id in BATTERY_FEATURES {
+            let feature_index = match self.get_feature_index(device, device_index, feature_id) {
+                Ok(Some(index)) => index,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Failed to resolve battery feature 0x{:04x}: {}", feature_id, e);
+                    return Ok(None);
+                }
+            };
+
+            let status = match feature_id {
+                FEATURE_UNIFIED_BATTERY => self.read_unified_battery(device, device_index, feature_index),
+                FEATURE_BATTERY_STATUS => self.read_battery_status(device, device_index, feature_index),
+                _ => self.read_battery_voltage(device, device_index, feature_index),
+            };
+
+            return match status {
+                Ok(status) => {
+                    debug!("Battery status read via 0x{:04x}: {:?}", feature_id, status);
+                    Ok(Some(status))
+                }
+                Err(e) => {
+                    warn!("Failed to read battery status via 0x{:04x}: {}", feature_id, e);
+                    Ok(None)
+                }
+            };
+        }
+
+        warn!("Device supports none of the battery features {:04x?}", BATTERY_FEATURES);
+        Ok(None)
+    }
+
+    fn read_unified_battery(&self, device: &HidppDevice, device_index: u8, feature_index: u8) -> Result<BatteryStatus> {
+        let params = self.request(device, device_index, feature_index, UNIFIED_BATTERY_GET_CAPABILITIES, &[])?;
+        let capabilities = UnifiedBatteryCapabilities::from_params(&params);
+
+        let params = self.request(device, device_index, feature_index, UNIFIED_BATTERY_GET_STATUS, &[])?;
+        BatteryStatus::from_unified_battery(&capabilities, &params)
+    }
+
+    fn read_battery_status(&self, device: &HidppDevice, device_index: u8, feature_index: u8) -> Result<BatteryStatus> {
+        let params = self.request(device, device_index, feature_index, BATTERY_STATUS_GET_LEVEL_STATUS, &[])?;
+        BatteryStatus::from_battery_status(&params)
+    }
+
+    fn read_battery_voltage(&self, device: &HidppDevice, device_index: u8, feature_index: u8) -> Result<BatteryStatus> {
+        let params = self.request(device, device_index, feature_index, BATTERY_VOLTAGE_GET_VOLTAGE, &[])?;
+        Ok(BatteryStatus::from_battery_voltage(&self.voltage_curve, &params))
+    }
+
+    /// Send a HID++ 2.0 request and return the parameter bytes of the reply.
+    ///
+    /// The request goes out in the smallest report type the device accepts,
+    /// stamped with a fresh software ID so its reply can be told apart from
+    /// notifications and stale replies.
+    fn request(&self, device: &HidppDevice, device_index: u8, feature_index: u8, function: u8, params: &[u8]) -> Result<Vec<u8>> {
+        let request = HidppMessage {
+            report_type: device.reports.pick(params.len())?,
+            device_index,
+            feature_index,
+            function_byte: (function << 4) | device.next_software_id(),
+            params: params.to_vec(),
+        };
+
+        let reply = self.transact(device, &request)?;
+
+        // Feature parsers index up to the long report size, pad short replies
+        let mut params = reply.params;
+        params.resize(params.len().max(LONG_REPORT_LEN - 4), 0);
+        Ok(params)
+    }
+
+    /// Read a HID++ 1.0 long register of the receiver and return its data.
+    fn read_long_register(&self, receiver: &HidppDevice, register: u8, params: &[u8]) -> Result<Vec<u8>> {
+        // Register access carries the register address where HID++ 2.0 has
+        // the function byte, so there is no room for a software ID
+        let request = HidppMessage {
+            report_type: ReportType::Short,
+            device_index: RECEIVER_DEVICE_INDEX,
+            feature_index: GET_LONG_REGISTER,
+            function_byte: register,
+            params: params.to_vec(),
+        };
+
+        Ok(self.transact(receiver, &request)?.params)
+    }
+
+    /// Write `request` and wait for its reply, discarding unrelated frames.
+    ///
+    /// HID++ error replies are returned as `HidppError`.
+    fn transact(&self, device: &HidppDevice, request: &HidppMessage) -> Result<HidppMessage> {
+        device.device.write(&request.encode()?)?;
+
+        let deadline = Instant::now() + Duration::from_millis(READ_TIMEOUT_MS as u64);
+        let mut buf = [0u8; VERY_LONG_REPORT_LEN];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(HidppError::Timeout(READ_TIMEOUT_MS).into());
+            }
+
+            let bytes_read = device.device.read_timeout(&mut buf, remaining.as_millis() as i32)?;
+            if bytes_read == 0 {
+                continue;
+            }
+
+            let frame = match HidppMessage::decode(&buf[..bytes_read]) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    debug!("Discarding frame from {}: {}", device.path, e);
+                    continue;
+                }
+            };
+            match frame.match_reply(request) {
+                ReplyMatch::Reply => return Ok(frame),
+                ReplyMatch::Error(e) => return Err(e.into()),
+                ReplyMatch::Unrelated => {
+                    debug!("Discarding unrelated HID++ frame from {}: {:02x?}", device.path, &buf[..bytes_read]);
+                }
+            }
+        }
+    }
+}
+
+impl BatterySource for LogitechManager {
+    fn read_battery(&mut self, vendor_id: u16, product_id: u16) -> Result<Option<BatteryStatus>> {
+        self.get_battery_level(vendor_id, product_id)
+    }
+}
+
+impl HidppDevice {
+    fn new(device: Box<dyn HidConnection>, path: String, reports: SupportedReports, transport: DeviceTransport) -> Self {
+        Self { device, path, reports, transport, software_id: Cell::new(0) }
+    }
+
+    fn next_software_id(&self) -> u8 {
+        // Cycle through 1..=15, 0 marks device-initiated notifications
+        let software_id = self.software_id.get() % MAX_SOFTWARE_ID + 1;
+        self.software_id.set(software_id);
+        software_id
+    }
+}
+
+fn matches_selector(info: &HidInterfaceInfo, selector: &HidSelector) -> bool {
+    if let Some(name) = &selector.name {
+        let product = info.product_string.as_deref().unwrap_or_default().to_lowercase();
+        if !product.contains(&name.to_lowercase()) {
+            return false;
+        }
+    }
+    if let Some(serial_number) = &selector.serial_number {
+        if info.serial_number.as_deref() != Some(serial_number.as_str()) {
+            return false;
+        }
+    }
+    if let Some(path) = &selector.path {
+        if info.path != *path {
+            return false;
+        }
+    }
+    if let Some(usage_page) = selector.usage_page {
+        if info.usage_page != usage_page {
+            return false;
+        }
+    }
+    if let Some(interface_number) = selector.interface_number {
+        if info.interface_number != interface_number {
+            return false;
+        }
+    }
+    true
+}
+
+fn receiver_kind(product_id: u16) -> Option<ReceiverKind> {
+    if UNIFYING_RECEIVER_PRODUCT_IDS.contains(&product_id) {
+        Some(ReceiverKind::Unifying)
+    } else if BOLT_RECEIVER_PRODUCT_IDS.contains(&product_id) {
+        Some(ReceiverKind::Bolt)
+    } else {
+        None
+    }
+}
+
+fn is_empty_slot_error(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<HidppError>(),
+        Some(HidppError::Hidpp10(Hidpp10Error::InvalidValue | Hidpp10Error::UnknownDevice | Hidpp10Error::InvalidParamValue))
+    )
+}
+
+fn parse_device_name(kind: ReceiverKind, data: &[u8]) -> Option<String> {
+    // Unifying: sub-register, length, name. Bolt: sub-register, part, length, name.
+    let offset = match kind {
+        ReceiverKind::Unifying => 1,
+        ReceiverKind::Bolt => 2,
+    };
+    let len = *data.get(offset)? as usize;
+    let name = data.get(offset + 1..offset + 1 + len)?;
+    let name = String::from_utf8_lossy(name).trim_end_matches('\0').to_string();
+    (!name.is_empty()).then_some(name)
+}
+
+impl DeviceKind {
+    fn from_code(code: u8) -> Self {
+        match code {
+            0x01 => DeviceKind::Keyboard,
+            0x02 => DeviceKind::Mouse,
+            0x03 => DeviceKind::Numpad,
+            0x04 => DeviceKind::Presenter,
+            0x07 => DeviceKind::Remote,
+            0x08 => DeviceKind::Trackball,
+            0x09 => DeviceKind::Touchpad,
+            other => DeviceKind::Other(other),
+        }
+    }
+}
+
+impl DeviceKind {
+    // DeviceNameType (0x0005) uses its own numbering
+    fn from_name_type(code: u8) -> Self {
+        match code {
+            0 => DeviceKind::Keyboard,
+            1 => DeviceKind::Remote,
+            2 => DeviceKind::Numpad,
+            3 => DeviceKind::Mouse,
+            4 => DeviceKind::Touchpad,
+            5 => DeviceKind::Trackball,
+            6 => DeviceKind::Presenter,
+            other => DeviceKind::Other(other),
+        }
+    }
+}
+
+impl FirmwareVersion {
+    fn from_params(params: &[u8]) -> Self {
+        // getFwInfo: type, prefix (3 ASCII chars), number and revision (BCD), build (big endian)
+        let kind = match params[0] & 0x0F {
+            0 => FirmwareKind::Firmware,
+            1 => FirmwareKind::Bootloader,
+            2 => FirmwareKind::Hardware,
+            other => FirmwareKind::Other(other),
+        };
+
+        match kind {
+            FirmwareKind::Firmware | FirmwareKind::Bootloader => {
+                let build = u16::from_be_bytes([params[6], params[7]]);
+                let mut version = format!("{:02X}.{:02X}", params[4], params[5]);
+                if build != 0 {
+                    version.push_str(&format!(".B{:04X}", build));
+                }
+                Self {
+                    kind,
+                    name: String::from_utf8_lossy(&params[1..4]).trim_end_matches('\0').to_string(),
+                    version,
+                }
             }
+            _ => Self { kind, name: String::new(), version: params[1].to_string() },
         }
     }
 }
 
+impl DeviceInfo {
+    pub fn supports(&self, feature_id: u16) -> bool {
+        self.features.iter().any(|f| f.id == feature_id)
+    }
+
+    pub fn has_battery_feature(&self) -> bool {
+        BATTERY_FEATURES.iter().any(|feature_id| self.supports(*feature_id))
+    }
+
+    pub fn main_firmware(&self) -> Option<&FirmwareVersion> {
+        self.firmware.iter().find(|fw| fw.kind == FirmwareKind::Firmware)
+    }
+}
+
+impl PairedDevice {
+    fn from_pairing_info(kind: ReceiverKind, slot: u8, info: &[u8], name: Option<String>) -> Result<Self> {
+        let min_len = match kind {
+            ReceiverKind::Unifying => 8,
+            ReceiverKind::Bolt => 4,
+        };
+        if info.len() < min_len {
+            bail!("Short pairing info of slot {}: {:02x?}", slot, info);
+        }
+
+        // Data starts with the echoed sub-register
+        let (wireless_product_id, kind_code) = match kind {
+            // sub-register, destination ID, report interval, WPID (big endian), ..., device type
+            ReceiverKind::Unifying => (u16::from_be_bytes([info[3], info[4]]), info[7]),
+            // sub-register, device type, WPID (little endian)
+            ReceiverKind::Bolt => (u16::from_le_bytes([info[2], info[3]]), info[1]),
+        };
+
+        Ok(Self {
+            slot,
+            wireless_product_id,
+            kind: DeviceKind::from_code(kind_code & 0x0F),
+            name,
+        })
+    }
+}
+
+impl UnifiedBatteryCapabilities {
+    pub(super) fn from_params(params: &[u8]) -> Self {
+        Self {
+            supported_levels: params[0],
+            rechargeable: params[1] & CAPABILITY_RECHARGEABLE != 0,
+            state_of_charge: params[1] & CAPABILITY_STATE_OF_CHARGE != 0,
+        }
+    }
+}
+
+impl fmt::Display for DeviceTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceTransport::Usb => write!(f, "usb"),
+            DeviceTransport::Bluetooth => write!(f, "bluetooth"),
+            DeviceTransport::Receiver(ReceiverKind::Unifying) => write!(f, "unifying-receiver"),
+            DeviceTransport::Receiver(ReceiverKind::Bolt) => write!(f, "bolt-receiver"),
+            DeviceTransport::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+impl BatteryLevel {
+    fn from_flags(flags: u8) -> Self {
+        // Devices are expected to set a single bit, prefer the most urgent one otherwise
+        if flags & LEVEL_CRITICAL != 0 {
+            BatteryLevel::Critical
+        } else if flags & LEVEL_LOW != 0 {
+            BatteryLevel::Low
+        } else if flags & LEVEL_GOOD != 0 {
+            BatteryLevel::Good
+        } else if flags & LEVEL_FULL != 0 {
+            BatteryLevel::Full
+        } else {
+            BatteryLevel::Unknown
+        }
+    }
+
+    pub(crate) fn from_percentage(percentage: u8) -> Self {
+        match percentage {
+            0..=5 => BatteryLevel::Critical,
+            6..=20 => BatteryLevel::Low,
+            21..=89 => BatteryLevel::Good,
+            _ => BatteryLevel::Full,
+        }
+    }
+
+    /// Rough percentage for devices that only report a level bucket,
+    /// using the same approximations as Solaar.
+    pub fn approximate_percentage(&self) -> Option<u8> {
+        match self {
+            BatteryLevel::Critical => Some(5),
+            BatteryLevel::Low => Some(20),
+            BatteryLevel::Good => Some(50),
+            BatteryLevel::Full => Some(90),
+            BatteryLevel::Unknown => None,
+        }
+    }
+}
+
+impl ChargingStatus {
+    fn from_unified_battery(code: u8) -> Result<Self> {
+        match code {
+            0 => Ok(ChargingStatus::Discharging),
+            1 => Ok(ChargingStatus::Charging),
+            2 => Ok(ChargingStatus::SlowCharging),
+            3 => Ok(ChargingStatus::Full),
+            4 => Ok(ChargingStatus::Error),
+            other => bail!("Unknown UnifiedBattery charging status: {}", other),
+        }
+    }
+
+    fn from_battery_status(code: u8) -> Result<Self> {
+        match code {
+            0 => Ok(ChargingStatus::Discharging),
+            // Recharging, and almost full (final charging stage)
+            1 | 2 => Ok(ChargingStatus::Charging),
+            3 => Ok(ChargingStatus::Full),
+            4 => Ok(ChargingStatus::SlowCharging),
+            // Invalid battery type, thermal error, other charging error
+            5..=7 => Ok(ChargingStatus::Error),
+            other => bail!("Unknown BatteryStatus charging status: {}", other),
+        }
+    }
+
+    pub fn is_charging(&self) -> bool {
+        matches!(self, ChargingStatus::Charging | ChargingStatus::SlowCharging)
+    }
+}
+
+impl BatteryStatus {
+    /// Exact state of charge when known, otherwise the level bucket estimate.
+    pub fn percentage_or_estimate(&self) -> Option<u8> {
+        self.percentage.or_else(|| self.level.approximate_percentage())
+    }
+
+    pub(super) fn from_unified_battery(capabilities: &UnifiedBatteryCapabilities, params: &[u8]) -> Result<Self> {
+        // get_status: state of charge, level flags, charging status, external power
+        let percentage = if capabilities.state_of_charge {
+            Some(params[0].min(100))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            percentage,
+            level: BatteryLevel::from_flags(params[1]),
+            charging: ChargingStatus::from_unified_battery(params[2])?,
+            external_power: params[3] != 0,
+            voltage_mv: None,
+        })
+    }
+
+    pub(super) fn from_battery_status(params: &[u8]) -> Result<Self> {
+        // GetBatteryLevelStatus: discharge level, next level, status.
+        // A zero level means the device does not report one.
+        let percentage = match params[0] {
+            0 => None,
+            level => Some(level.min(100)),
+        };
+        let charging = ChargingStatus::from_battery_status(params[2])?;
+
+        Ok(Self {
+            percentage,
+            level: percentage.map(BatteryLevel::from_percentage).unwrap_or(BatteryLevel::Unknown),
+            charging,
+            external_power: charging != ChargingStatus::Discharging,
+            voltage_mv: None,
+        })
+    }
+
+    pub(super) fn from_battery_voltage(curve: &VoltageCurve, params: &[u8]) -> Self {
+        // GetBatteryVoltage: voltage in mV (big endian), flags
+        let voltage_mv = u16::from_be_bytes([params[0], params[1]]);
+        let flags = params[2];
+        let percentage = curve.percentage(voltage_mv);
+
+        let external_power = flags & VOLTAGE_FLAG_EXTERNAL_POWER != 0;
+        let charging = if !external_power {
+            ChargingStatus::Discharging
+        } else {
+            match flags & VOLTAGE_FLAG_CHARGE_STATUS_MASK {
+                0 if flags & VOLTAGE_FLAG_SLOW_CHARGE != 0 => ChargingStatus::SlowCharging,
+                0 => ChargingStatus::Charging,
+                1 => ChargingStatus::Full,
+                // Not charging while powered, or charging error
+                _ => ChargingStatus::Error,
+            }
+        };
+        let level = if flags & VOLTAGE_FLAG_CRITICAL != 0 {
+            BatteryLevel::Critical
+        } else {
+            BatteryLevel::from_percentage(percentage)
+        };
+
+        Self {
+            percentage: Some(percentage),
+            level,
+            charging,
+            external_power,
+            voltage_mv: Some(voltage_mv),
+        }
+    }
+}
+
+impl VoltageCurve {
+    pub fn new(mut points: Vec<VoltagePoint>) -> Result<Self> {
+        if points.len() < 2 {
+            bail!("Voltage curve needs at least 2 points, got {}", points.len());
+        }
+        if let Some(point) = points.iter().find(|p| p.percentage > 100) {
+            bail!("Voltage curve point {} mV maps to {}%, above 100%", point.millivolts, point.percentage);
+        }
+
+        points.sort_by_key(|point| std::cmp::Reverse(point.millivolts));
+        Ok(Self { points })
+    }
+
+    pub fn percentage(&self, millivolts: u16) -> u8 {
+        let highest = &self.points[0];
+        let lowest = &self.points[self.points.len() - 1];
+        if millivolts >= highest.millivolts {
+            return highest.percentage;
+        }
+        if millivolts <= lowest.millivolts {
+            return lowest.percentage;
+        }
+
+        // Points are sorted by descending voltage, so find the segment we fall into
+        let segment = self.points.windows(2)
+            .find(|w| millivolts <= w[0].millivolts && millivolts >= w[1].millivolts)
+            .expect("voltage within curve bounds");
+        let (upper, lower) = (&segment[0], &segment[1]);
+
+        let span_mv = (upper.millivolts - lower.millivolts) as i32;
+        if span_mv == 0 {
+            return upper.percentage;
+        }
+        let span_pct = upper.percentage as i32 - lower.percentage as i32;
+        let offset_mv = (millivolts - lower.millivolts) as i32;
+        (lower.percentage as i32 + span_pct * offset_mv / span_mv).clamp(0, 100) as u8
+    }
+}
+
+impl Default for VoltageCurve {
+    fn default() -> Self {
+        let points = LI_ION_VOLTAGE_CURVE.iter()
+            .map(|&(millivolts, percentage)| VoltagePoint { millivolts, percentage })
+            .collect();
+        Self { points }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::hidpp::HidppError;
+    use crate::hardware::mock::{MockBattery, MockDevice, MockTransport, ERROR_INVALID_ARGUMENT};
+
+    const MOCK_PRODUCT_ID: u16 = 0xB369;
+
+    const MX_KEYS_MINI_CAPABILITIES: UnifiedBatteryCapabilities = UnifiedBatteryCapabilities {
+        supported_levels: LEVEL_CRITICAL | LEVEL_LOW | LEVEL_GOOD | LEVEL_FULL,
+        rechargeable: true,
+        state_of_charge: true,
+    };
+
+    #[test]
+    fn test_parse_unified_battery_capabilities() {
+        let capabilities = UnifiedBatteryCapabilities::from_params(&[0x0F, 0x03, 0x00]);
+        assert_eq!(capabilities, MX_KEYS_MINI_CAPABILITIES);
+    }
+
+    #[test]
+    fn test_parse_unified_battery_charging() {
+        let status = BatteryStatus::from_unified_battery(&MX_KEYS_MINI_CAPABILITIES, &[85, LEVEL_GOOD, 1, 1]).unwrap();
+        assert_eq!(status, BatteryStatus {
+            percentage: Some(85),
+            level: BatteryLevel::Good,
+            charging: ChargingStatus::Charging,
+            external_power: true,
+            voltage_mv: None,
+        });
+        assert!(status.charging.is_charging());
+    }
+
+    #[test]
+    fn test_parse_unified_battery_without_state_of_charge() {
+        let capabilities = UnifiedBatteryCapabilities { state_of_charge: false, ..MX_KEYS_MINI_CAPABILITIES };
+        let status = BatteryStatus::from_unified_battery(&capabilities, &[0, LEVEL_LOW, 0, 0]).unwrap();
+        assert_eq!(status.percentage, None);
+        assert_eq!(status.level, BatteryLevel::Low);
+        assert_eq!(status.charging, ChargingStatus::Discharging);
+    }
+
+    #[test]
+    fn test_parse_unified_battery_unknown_charging_status() {
+        let result = BatteryStatus::from_unified_battery(&MX_KEYS_MINI_CAPABILITIES, &[50, LEVEL_GOOD, 9, 0]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_battery_status() {
+        let status = BatteryStatus::from_battery_status(&[50, 20, 0]).unwrap();
+        assert_eq!(status.percentage, Some(50));
+        assert_eq!(status.level, BatteryLevel::Good);
+        assert_eq!(status.charging, ChargingStatus::Discharging);
+        assert!(!status.external_power);
+
+        let status = BatteryStatus::from_battery_status(&[90, 0, 2]).unwrap();
+        assert_eq!(status.charging, ChargingStatus::Charging);
+        assert!(status.external_power);
+    }
+
+    #[test]
+    fn test_parse_battery_status_without_level() {
+        let status = BatteryStatus::from_battery_status(&[0, 0, 3]).unwrap();
+        assert_eq!(status.percentage, None);
+        assert_eq!(status.level, BatteryLevel::Unknown);
+        assert_eq!(status.charging, ChargingStatus::Full);
+    }
+
+    #[test]
+    fn test_parse_battery_voltage() {
+        // 3811 mV, discharging
+        let status = BatteryStatus::from_battery_voltage(&VoltageCurve::default(), &[0x0E, 0xE3, 0x00]);
+        assert_eq!(status.voltage_mv, Some(3811));
+        assert_eq!(status.percentage, Some(50));
+        assert_eq!(status.charging, ChargingStatus::Discharging);
+
+        // 4100 mV, external power, charging
+        let status = BatteryStatus::from_battery_voltage(&VoltageCurve::default(), &[0x10, 0x04, 0x80]);
+        assert_eq!(status.charging, ChargingStatus::Charging);
+        assert!(status.external_power);
+
+        // External power, charge complete
+        let status = BatteryStatus::from_battery_voltage(&VoltageCurve::default(), &[0x10, 0x5A, 0x81]);
+        assert_eq!(status.charging, ChargingStatus::Full);
+    }
+
+    #[test]
+    fn test_voltage_curve_interpolation() {
+        let curve = VoltageCurve::default();
+        assert_eq!(curve.percentage(4300), 100);
+        assert_eq!(curve.percentage(3400), 0);
+        assert_eq!(curve.percentage(3922), 70);
+        // Halfway between 3859 mV (60%) and 3922 mV (70%)
+        assert_eq!(curve.percentage(3890), 64);
+    }
+
+    #[test]
+    fn test_custom_voltage_curve() {
+        // Two AA alkaline cells in series
+        let curve = VoltageCurve::new(vec![
+            VoltagePoint { millivolts: 2200, percentage: 0 },
+            VoltagePoint { millivolts: 3200, percentage: 100 },
+        ]).unwrap();
+        assert_eq!(curve.percentage(2700), 50);
+
+        assert!(VoltageCurve::new(vec![VoltagePoint { millivolts: 3000, percentage: 50 }]).is_err());
+        assert!(VoltageCurve::new(vec![
+            VoltagePoint { millivolts: 3000, percentage: 150 },
+            VoltagePoint { millivolts: 2000, percentage: 0 },
+        ]).is_err());
+    }
+
+    #[test]
+    fn test_parse_unifying_pairing_info() {
+        let info = [0x21, 0x5C, 0x08, 0x40, 0x82, 0x00, 0x00, 0x02, 0x00, 0x00];
+        let name = parse_device_name(ReceiverKind::Unifying, &[0x41, 0x04, b'M', b'X', b' ', b'3', 0x00]);
+        let device = PairedDevice::from_pairing_info(ReceiverKind::Unifying, 2, &info, name).unwrap();
+        assert_eq!(device, PairedDevice {
+            slot: 2,
+            wireless_product_id: 0x4082,
+            kind: DeviceKind::Mouse,
+            name: Some("MX 3".to_string()),
+        });
+
+        assert!(PairedDevice::from_pairing_info(ReceiverKind::Unifying, 2, &info[..7], None).is_err());
+    }
+
+    #[test]
+    fn test_parse_bolt_pairing_info() {
+        let info = [0x51, 0x01, 0x69, 0xB3, 0x00, 0x00];
+        let name = parse_device_name(ReceiverKind::Bolt, &[0x61, 0x01, 0x0C, b'M', b'X', b' ', b'K', b'e', b'y', b's', b' ', b'M', b'i', b'n', b'i']);
+        let device = PairedDevice::from_pairing_info(ReceiverKind::Bolt, 1, &info, name).unwrap();
+        assert_eq!(device.wireless_product_id, 0xB369);
+        assert_eq!(device.kind, DeviceKind::Keyboard);
+        assert_eq!(device.name.as_deref(), Some("MX Keys Mini"));
+        assert!(PairedDevice::from_pairing_info(ReceiverKind::Bolt, 1, &info[..3], None).is_err());
+    }
+
+    #[test]
+    fn test_receiver_kind() {
+        assert_eq!(receiver_kind(0xC52B), Some(ReceiverKind::Unifying));
+        assert_eq!(receiver_kind(0xC548), Some(ReceiverKind::Bolt));
+        assert_eq!(receiver_kind(0xB369), None);
+    }
+
+    #[test]
+    fn test_parse_firmware_version() {
+        let params = [0x00, b'M', b'P', b'K', 0x12, 0x03, 0x00, 0x11, 0x01, 0x00];
+        assert_eq!(FirmwareVersion::from_params(&params), FirmwareVersion {
+            kind: FirmwareKind::Firmware,
+            name: "MPK".to_string(),
+            version: "12.03.B0011".to_string(),
+        });
+
+        let params = [0x02, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(FirmwareVersion::from_params(&params), FirmwareVersion {
+            kind: FirmwareKind::Hardware,
+            name: String::new(),
+            version: "5".to_string(),
+        });
+    }
+
+    fn mock_manager(device: &MockDevice) -> LogitechManager {
+        let mut transport = MockTransport::new();
+        transport.add_device(LOGITECH_VENDOR_ID, MOCK_PRODUCT_ID, "/dev/hidraw0", device.clone());
+        LogitechManager::with_transport(Box::new(transport))
+    }
+
+    #[test]
+    fn test_read_battery_from_mock_device() {
+        let device = MockDevice::new(MockBattery::discharging(42));
+        let mut manager = mock_manager(&device);
+
+        let status = manager.get_battery_level(LOGITECH_VENDOR_ID, MOCK_PRODUCT_ID).unwrap().unwrap();
+        assert_eq!(status, BatteryStatus {
+            percentage: Some(42),
+            level: BatteryLevel::Good,
+            charging: ChargingStatus::Discharging,
+            external_power: false,
+            voltage_mv: None,
+        });
+
+        device.set_battery(MockBattery::charging(43));
+        let status = manager.get_battery_level(LOGITECH_VENDOR_ID, MOCK_PRODUCT_ID).unwrap().unwrap();
+        assert_eq!(status.percentage, Some(43));
+        assert!(status.charging.is_charging());
+        assert!(status.external_power);
+    }
+
+    #[test]
+    fn test_feature_index_is_cached_across_polls() {
+        let device = MockDevice::new(MockBattery::discharging(42));
+        let mut manager = mock_manager(&device);
+
+        manager.get_battery_level(LOGITECH_VENDOR_ID, MOCK_PRODUCT_ID).unwrap();
+        manager.get_battery_level(LOGITECH_VENDOR_ID, MOCK_PRODUCT_ID).unwrap();
+
+        let root_requests = device.requests().iter()
+            .filter(|request| request.feature_index == ROOT_FEATURE_INDEX)
+            .count();
+        assert_eq!(root_requests, 1);
+        // Capabilities and status on each poll
+        assert_eq!(device.requests().len(), 5);
+    }
+
+    #[test]
+    fn test_read_device_info_from_mock_device() {
+        let device = MockDevice::new(MockBattery::discharging(42));
+        let mut manager = mock_manager(&device);
+
+        let info = manager.get_device_info(LOGITECH_VENDOR_ID, MOCK_PRODUCT_ID).unwrap().unwrap();
+        let ids: Vec<u16> = info.features.iter().map(|f| f.id).collect();
+        assert_eq!(ids, vec![FEATURE_ROOT, FEATURE_FEATURE_SET, FEATURE_UNIFIED_BATTERY]);
+        assert!(info.has_battery_feature());
+        assert_eq!(info.name, None);
+
+        // The feature set seeded the cache, reading the battery needs no lookup
+        let before = device.requests().len();
+        manager.get_battery_level(LOGITECH_VENDOR_ID, MOCK_PRODUCT_ID).unwrap();
+        assert!(device.requests()[before..].iter().all(|r| r.feature_index != ROOT_FEATURE_INDEX));
+    }
+
+    #[test]
+    fn test_hidpp_error_drops_device() {
+        let device = MockDevice::new(MockBattery::discharging(42));
+        let mut manager = mock_manager(&device);
+        device.fail_feature(2, ERROR_INVALID_ARGUMENT);
+
+        let status = manager.get_battery_level(LOGITECH_VENDOR_ID, MOCK_PRODUCT_ID).unwrap();
+        assert_eq!(status, None);
+        assert!(manager.hid_device.is_none());
+        assert!(manager.feature_cache.is_empty());
+    }
+
+    #[test]
+    fn test_unanswered_request_times_out() {
+        let device = MockDevice::new(MockBattery::discharging(42));
+        let mut manager = mock_manager(&device);
+        manager.select_hid_device(LOGITECH_VENDOR_ID, MOCK_PRODUCT_ID).unwrap();
+        device.set_responding(false);
+
+        let (hidpp_device, device_index) = manager.hid_device.take().unwrap();
+        let error = manager.get_feature_index(&hidpp_device, device_index, FEATURE_UNIFIED_BATTERY).unwrap_err();
+        assert_eq!(error.downcast_ref::<HidppError>(), Some(&HidppError::Timeout(READ_TIMEOUT_MS)));
+    }
+
+    #[test]
+    fn test_unplugged_mock_device() {
+        let device = MockDevice::new(MockBattery::discharging(42));
+        let mut manager = mock_manager(&device);
+        manager.get_battery_level(LOGITECH_VENDOR_ID, MOCK_PRODUCT_ID).unwrap();
+
+        device.unplug();
+        assert_eq!(manager.get_battery_level(LOGITECH_VENDOR_ID, MOCK_PRODUCT_ID).unwrap(), None);
+        assert!(manager.hid_device.is_none());
+        // No longer enumerated either
+        assert_eq!(manager.get_battery_level(LOGITECH_VENDOR_ID, MOCK_PRODUCT_ID).unwrap(), None);
+    }
+
+    #[test]
+    fn test_skips_interfaces_without_hidpp_collection() {
+        // Keyboard interface listed first, and posing with a vendor usage page
+        // as some enumerations report for the whole node
+        let keyboard_descriptor = [0x06, 0x00, 0xFF, 0x09, 0x06, 0xA1, 0x01, 0x85, 0x01, 0xC0];
+        let keyboard = MockDevice::new(MockBattery::discharging(10)).with_report_descriptor(&keyboard_descriptor);
+        let device = MockDevice::new(MockBattery::discharging(42));
+
+        let mut transport = MockTransport::new();
+        transport.add_device(LOGITECH_VENDOR_ID, MOCK_PRODUCT_ID, "/dev/hidraw0", keyboard.clone());
+        transport.add_device(LOGITECH_VENDOR_ID, MOCK_PRODUCT_ID, "/dev/hidraw1", device.clone());
+        let mut manager = LogitechManager::with_transport(Box::new(transport));
+
+        let status = manager.get_battery_level(LOGITECH_VENDOR_ID, MOCK_PRODUCT_ID).unwrap().unwrap();
+        assert_eq!(status.percentage, Some(42));
+        assert!(keyboard.requests().is_empty());
+        assert_eq!(manager.hid_device.as_ref().unwrap().0.reports, SupportedReports::default());
+    }
+
+    #[test]
+    fn test_reads_battery_over_bluetooth() {
+        // Vendor collection on 0xFF43 with long reports only, as over BLE
+        let ble_descriptor = [0x06, 0x43, 0xFF, 0x0A, 0x02, 0x06, 0xA1, 0x01, 0x85, 0x11, 0x95, 0x13, 0x81, 0x00, 0xC0];
+        let device = MockDevice::new(MockBattery::charging(64)).with_report_descriptor(&ble_descriptor);
+        // The Bolt receiver, the keyboard itself is paired over Bluetooth
+        let receiver_product_id = 0xC548;
+
+        let mut transport = MockTransport::new();
+        transport.add_bluetooth_device(LOGITECH_VENDOR_ID, MOCK_PRODUCT_ID, "/dev/hidraw4", device.clone());
+        let mut manager = LogitechManager::with_transport(Box::new(transport));
+
+        assert_eq!(manager.get_battery_level(LOGITECH_VENDOR_ID, receiver_product_id).unwrap(), None);
+
+        manager.set_bluetooth_product_ids(vec![MOCK_PRODUCT_ID]);
+        let status = manager.get_battery_level(LOGITECH_VENDOR_ID, receiver_product_id).unwrap().unwrap();
+        assert_eq!(status.percentage, Some(64));
+        assert_eq!(manager.selected_transport(), Some(DeviceTransport::Bluetooth));
+        assert!(device.requests().iter().all(|r| r.report_type == ReportType::Long));
+    }
+
+    #[test]
+    fn test_prefers_usb_over_bluetooth() {
+        let bluetooth = MockDevice::new(MockBattery::discharging(10));
+        let usb = MockDevice::new(MockBattery::charging(20));
+
+        let mut transport = MockTransport::new();
+        transport.add_bluetooth_device(LOGITECH_VENDOR_ID, MOCK_PRODUCT_ID, "/dev/hidraw0", bluetooth.clone());
+        transport.add_device(LOGITECH_VENDOR_ID, MOCK_PRODUCT_ID, "/dev/hidraw1", usb);
+        let mut manager = LogitechManager::with_transport(Box::new(transport));
+        manager.set_bluetooth_product_ids(vec![MOCK_PRODUCT_ID]);
+
+        let status = manager.get_battery_level(LOGITECH_VENDOR_ID, MOCK_PRODUCT_ID).unwrap().unwrap();
+        assert_eq!(status.percentage, Some(20));
+        assert_eq!(manager.selected_transport(), Some(DeviceTransport::Usb));
+        assert!(bluetooth.requests().is_empty());
+    }
+}
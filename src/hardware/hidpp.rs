@@ -0,0 +1,409 @@
+use std::fmt;
+use anyhow::{anyhow, bail, Result};
+
+// HID++ report IDs and their total lengths (report ID included)
+pub const SHORT_REPORT_ID: u8 = 0x10;
+pub const LONG_REPORT_ID: u8 = 0x11;
+pub const VERY_LONG_REPORT_ID: u8 = 0x12;
+pub const SHORT_REPORT_LEN: usize = 7;
+pub const LONG_REPORT_LEN: usize = 20;
+pub const VERY_LONG_REPORT_LEN: usize = 64;
+
+// Report ID, device index, feature index, function/software ID
+const HEADER_LEN: usize = 4;
+
+// Feature index / sub ID of error replies
+const HIDPP10_ERROR_SUB_ID: u8 = 0x8F;
+const HIDPP20_ERROR_FEATURE_INDEX: u8 = 0xFF;
+
+// Software IDs live in the low nibble of the function byte, 0 is reserved for notifications
+pub const MAX_SOFTWARE_ID: u8 = 0x0F;
+
+// Vendor usage pages of the collections carrying HID++, the first one is
+// used by receivers and wired devices, the second by Bluetooth devices
+pub const HIDPP_USAGE_PAGES: [u16; 2] = [0xFF00, 0xFF43];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportType {
+    Short,
+    Long,
+    VeryLong,
+}
+
+impl ReportType {
+    pub fn from_id(report_id: u8) -> Option<Self> {
+        match report_id {
+            SHORT_REPORT_ID => Some(ReportType::Short),
+            LONG_REPORT_ID => Some(ReportType::Long),
+            VERY_LONG_REPORT_ID => Some(ReportType::VeryLong),
+            _ => None,
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        match self {
+            ReportType::Short => SHORT_REPORT_ID,
+            ReportType::Long => LONG_REPORT_ID,
+            ReportType::VeryLong => VERY_LONG_REPORT_ID,
+        }
+    }
+
+    pub fn report_len(&self) -> usize {
+        match self {
+            ReportType::Short => SHORT_REPORT_LEN,
+            ReportType::Long => LONG_REPORT_LEN,
+            ReportType::VeryLong => VERY_LONG_REPORT_LEN,
+        }
+    }
+
+    pub fn max_params(&self) -> usize {
+        self.report_len() - HEADER_LEN
+    }
+}
+
+/// HID++ report types a device accepts, as advertised by its report descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SupportedReports {
+    pub short: bool,
+    pub long: bool,
+    pub very_long: bool,
+}
+
+impl Default for SupportedReports {
+    // Every HID++ device is expected to handle short and long reports
+    fn default() -> Self {
+        Self { short: true, long: true, very_long: false }
+    }
+}
+
+impl SupportedReports {
+    /// Supported report types among `report_ids`, the short/long default
+    /// when none of them is a HID++ report ID.
+    pub fn from_report_ids(report_ids: &[u8]) -> Self {
+        let mut supported = Self { short: false, long: false, very_long: false };
+        for report_type in report_ids.iter().filter_map(|id| ReportType::from_id(*id)) {
+            match report_type {
+                ReportType::Short => supported.short = true,
+                ReportType::Long => supported.long = true,
+                ReportType::VeryLong => supported.very_long = true,
+            }
+        }
+
+        if supported.short || supported.long || supported.very_long {
+            supported
+        } else {
+            Self::default()
+        }
+    }
+
+    /// Pick the smallest supported report type that fits `params_len` parameter bytes.
+    pub fn pick(&self, params_len: usize) -> Result<ReportType> {
+        [
+            (self.short, ReportType::Short),
+            (self.long, ReportType::Long),
+            (self.very_long, ReportType::VeryLong),
+        ]
+            .into_iter()
+            .find(|(supported, report_type)| *supported && params_len <= report_type.max_params())
+            .map(|(_, report_type)| report_type)
+            .ok_or_else(|| anyhow!("No supported HID++ report type fits {} parameter bytes", params_len))
+    }
+}
+
+/// A single HID++ frame, request or reply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HidppMessage {
+    pub report_type: ReportType,
+    pub device_index: u8,
+    pub feature_index: u8,
+    // Function in the high nibble, software ID in the low nibble
+    pub function_byte: u8,
+    pub params: Vec<u8>,
+}
+
+impl HidppMessage {
+    /// Serialize into a report padded with zeros to the full report length.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        if self.params.len() > self.report_type.max_params() {
+            bail!("Too many parameters for a {:?} HID++ report: {}", self.report_type, self.params.len());
+        }
+
+        let mut report = vec![0u8; self.report_type.report_len()];
+        report[0] = self.report_type.id();
+        report[1] = self.device_index;
+        report[2] = self.feature_index;
+        report[3] = self.function_byte;
+        report[HEADER_LEN..HEADER_LEN + self.params.len()].copy_from_slice(&self.params);
+        Ok(report)
+    }
+
+    /// Parse a report read from the device, checking its report ID and length.
+    pub fn decode(report: &[u8]) -> Result<Self> {
+        let Some(report_type) = report.first().copied().and_then(ReportType::from_id) else {
+            bail!("Not a HID++ report: {:02x?}", report);
+        };
+        if report.len() != report_type.report_len() {
+            bail!("{:?} HID++ report has {} bytes, expected {}", report_type, report.len(), report_type.report_len());
+        }
+
+        Ok(Self {
+            report_type,
+            device_index: report[1],
+            feature_index: report[2],
+            function_byte: report[3],
+            params: report[HEADER_LEN..].to_vec(),
+        })
+    }
+}
+
+/// How a frame read from the device relates to an outstanding request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplyMatch {
+    Reply,
+    Error(HidppError),
+    // Notification, or reply to another request
+    Unrelated,
+}
+
+impl HidppMessage {
+    /// Classify `self` as the reply to `request`, an error reply to it, or neither.
+    pub fn match_reply(&self, request: &HidppMessage) -> ReplyMatch {
+        if self.device_index != request.device_index {
+            return ReplyMatch::Unrelated;
+        }
+
+        // Error replies shift the request header right by one byte:
+        // error marker, feature index / sub ID, function byte / address, error code
+        let echoes_request = self.function_byte == request.feature_index
+            && self.params[0] == request.function_byte;
+        match self.feature_index {
+            HIDPP20_ERROR_FEATURE_INDEX if echoes_request => {
+                ReplyMatch::Error(HidppError::Hidpp20(Hidpp20Error::from_code(self.params[1])))
+            }
+            HIDPP10_ERROR_SUB_ID if echoes_request => {
+                ReplyMatch::Error(HidppError::Hidpp10(Hidpp10Error::from_code(self.params[1])))
+            }
+            feature_index if feature_index == request.feature_index
+                && self.function_byte == request.function_byte => ReplyMatch::Reply,
+            _ => ReplyMatch::Unrelated,
+        }
+    }
+}
+
+/// Failure of a HID++ request, surfaced through `anyhow`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HidppError {
+    Hidpp10(Hidpp10Error),
+    Hidpp20(Hidpp20Error),
+    Timeout(u32),
+}
+
+/// Error codes of HID++ 1.0 error replies (sub ID 0x8F).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hidpp10Error {
+    InvalidSubId,
+    InvalidAddress,
+    InvalidValue,
+    ConnectFail,
+    TooManyDevices,
+    AlreadyExists,
+    Busy,
+    UnknownDevice,
+    ResourceError,
+    RequestUnavailable,
+    InvalidParamValue,
+    WrongPinCode,
+    Other(u8),
+}
+
+/// Error codes of HID++ 2.0 error replies (feature index 0xFF).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hidpp20Error {
+    Unknown,
+    InvalidArgument,
+    OutOfRange,
+    HardwareError,
+    LogitechInternal,
+    InvalidFeatureIndex,
+    InvalidFunctionId,
+    Busy,
+    Unsupported,
+    Other(u8),
+}
+
+impl Hidpp10Error {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0x01 => Hidpp10Error::InvalidSubId,
+            0x02 => Hidpp10Error::InvalidAddress,
+            0x03 => Hidpp10Error::InvalidValue,
+            0x04 => Hidpp10Error::ConnectFail,
+            0x05 => Hidpp10Error::TooManyDevices,
+            0x06 => Hidpp10Error::AlreadyExists,
+            0x07 => Hidpp10Error::Busy,
+            0x08 => Hidpp10Error::UnknownDevice,
+            0x09 => Hidpp10Error::ResourceError,
+            0x0A => Hidpp10Error::RequestUnavailable,
+            0x0B => Hidpp10Error::InvalidParamValue,
+            0x0C => Hidpp10Error::WrongPinCode,
+            other => Hidpp10Error::Other(other),
+        }
+    }
+}
+
+impl Hidpp20Error {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0x01 => Hidpp20Error::Unknown,
+            0x02 => Hidpp20Error::InvalidArgument,
+            0x03 => Hidpp20Error::OutOfRange,
+            0x04 => Hidpp20Error::HardwareError,
+            0x05 => Hidpp20Error::LogitechInternal,
+            0x06 => Hidpp20Error::InvalidFeatureIndex,
+            0x07 => Hidpp20Error::InvalidFunctionId,
+            0x08 => Hidpp20Error::Busy,
+            0x09 => Hidpp20Error::Unsupported,
+            other => Hidpp20Error::Other(other),
+        }
+    }
+}
+
+impl fmt::Display for HidppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HidppError::Hidpp10(e) => write!(f, "HID++ 1.0 error: {:?}", e),
+            HidppError::Hidpp20(e) => write!(f, "HID++ 2.0 error: {:?}", e),
+            HidppError::Timeout(ms) => write!(f, "no HID++ reply within {} ms", ms),
+        }
+    }
+}
+
+impl std::error::Error for HidppError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::descriptor::ReportDescriptor;
+
+    fn supported_reports(descriptor: &[u8]) -> SupportedReports {
+        SupportedReports::from_report_ids(&ReportDescriptor::parse(descriptor).unwrap().report_ids)
+    }
+
+    // Vendor collection of an MX Keys Mini over Bluetooth, long reports only
+    const LONG_ONLY_DESCRIPTOR: [u8; 18] = [
+        0x06, 0x43, 0xFF, // Usage Page (Vendor 0xFF43)
+        0x0A, 0x02, 0x06, // Usage (0x0602)
+        0xA1, 0x01,       // Collection (Application)
+        0x85, 0x11,       //   Report ID (0x11)
+        0x75, 0x08,       //   Report Size (8)
+        0x95, 0x13,       //   Report Count (19)
+        0x15, 0x00,       //   Logical Minimum (0)
+        0xC0,             // End Collection
+        0x00,
+    ];
+
+    #[test]
+    fn test_supported_reports_from_descriptor() {
+        let supported = supported_reports(&LONG_ONLY_DESCRIPTOR);
+        assert_eq!(supported, SupportedReports { short: false, long: true, very_long: false });
+        assert_eq!(supported.pick(2).unwrap(), ReportType::Long);
+    }
+
+    #[test]
+    fn test_supported_reports_ignores_report_id_in_data() {
+        // Usage (0x0085) followed by Report ID (0x10): only the item counts
+        let descriptor = [0x0A, 0x85, 0x11, 0x85, 0x10];
+        let supported = supported_reports(&descriptor);
+        assert_eq!(supported, SupportedReports { short: true, long: false, very_long: false });
+    }
+
+    #[test]
+    fn test_supported_reports_default_without_hidpp_ids() {
+        // Keyboard interface: Report ID (0x01)
+        let supported = supported_reports(&[0x05, 0x01, 0x85, 0x01]);
+        assert_eq!(supported, SupportedReports::default());
+        assert_eq!(supported.pick(3).unwrap(), ReportType::Short);
+        assert_eq!(supported.pick(4).unwrap(), ReportType::Long);
+        assert!(supported.pick(17).is_err());
+    }
+
+    #[test]
+    fn test_encode_pads_to_report_length() {
+        let message = HidppMessage {
+            report_type: ReportType::Long,
+            device_index: 0xFF,
+            feature_index: 0x00,
+            function_byte: 0x01,
+            params: vec![0x10, 0x04],
+        };
+        let report = message.encode().unwrap();
+        assert_eq!(report.len(), LONG_REPORT_LEN);
+        assert_eq!(&report[..6], &[0x11, 0xFF, 0x00, 0x01, 0x10, 0x04]);
+        assert!(report[6..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_decode_validates_report() {
+        let mut report = vec![0u8; LONG_REPORT_LEN];
+        report[..5].copy_from_slice(&[0x11, 0xFF, 0x02, 0x11, 0x55]);
+        let message = HidppMessage::decode(&report).unwrap();
+        assert_eq!(message.report_type, ReportType::Long);
+        assert_eq!(message.feature_index, 0x02);
+        assert_eq!(message.params[0], 0x55);
+
+        assert!(HidppMessage::decode(&report[..7]).is_err());
+        assert!(HidppMessage::decode(&[0x01, 0x00, 0x00]).is_err());
+    }
+
+    fn long_message(feature_index: u8, function_byte: u8, params: &[u8]) -> HidppMessage {
+        let mut padded = params.to_vec();
+        padded.resize(ReportType::Long.max_params(), 0);
+        HidppMessage {
+            report_type: ReportType::Long,
+            device_index: 0xFF,
+            feature_index,
+            function_byte,
+            params: padded,
+        }
+    }
+
+    #[test]
+    fn test_match_reply() {
+        let request = long_message(0x05, 0x13, &[]);
+        assert_eq!(long_message(0x05, 0x13, &[0x55]).match_reply(&request), ReplyMatch::Reply);
+        // Same function, other software ID
+        assert_eq!(long_message(0x05, 0x14, &[0x55]).match_reply(&request), ReplyMatch::Unrelated);
+        // Battery notification (software ID 0)
+        assert_eq!(long_message(0x05, 0x00, &[0x55]).match_reply(&request), ReplyMatch::Unrelated);
+    }
+
+    #[test]
+    fn test_match_hidpp20_error() {
+        let request = long_message(0x05, 0x13, &[]);
+        let error = long_message(HIDPP20_ERROR_FEATURE_INDEX, 0x05, &[0x13, 0x08]);
+        assert_eq!(error.match_reply(&request), ReplyMatch::Error(HidppError::Hidpp20(Hidpp20Error::Busy)));
+
+        // Error for a request with another software ID
+        let error = long_message(HIDPP20_ERROR_FEATURE_INDEX, 0x05, &[0x14, 0x09]);
+        assert_eq!(error.match_reply(&request), ReplyMatch::Unrelated);
+    }
+
+    #[test]
+    fn test_match_hidpp10_error() {
+        let request = HidppMessage {
+            report_type: ReportType::Short,
+            device_index: 0xFF,
+            feature_index: 0x81,
+            function_byte: 0x02,
+            params: vec![0; 3],
+        };
+        let error = HidppMessage {
+            report_type: ReportType::Short,
+            device_index: 0xFF,
+            feature_index: HIDPP10_ERROR_SUB_ID,
+            function_byte: 0x81,
+            params: vec![0x02, 0x03, 0x00],
+        };
+        assert_eq!(error.match_reply(&request), ReplyMatch::Error(HidppError::Hidpp10(Hidpp10Error::InvalidValue)));
+    }
+}
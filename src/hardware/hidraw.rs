@@ -0,0 +1,270 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use log::debug;
+
+use super::descriptor::ReportDescriptor;
+use super::transport::{BusType, HidConnection, HidInterfaceInfo, HidTransport};
+
+const SYSFS_HIDRAW_PATH: &str = "/sys/class/hidraw";
+const DEV_PATH: &str = "/dev";
+
+// Limits from linux/hidraw.h and linux/hid.h
+const HID_MAX_DESCRIPTOR_SIZE: usize = 4096;
+
+// ioctl request numbers from linux/hidraw.h, encoded as _IOR('H', nr, type)
+// with the asm-generic layout used by x86, arm and riscv
+const HIDIOCGRDESCSIZE: u32 = ior(0x01, std::mem::size_of::<libc::c_int>());
+const HIDIOCGRDESC: u32 = ior(0x02, std::mem::size_of::<RawReportDescriptor>());
+const HIDIOCGRAWINFO: u32 = ior(0x03, std::mem::size_of::<DevInfo>());
+
+// struct hidraw_devinfo
+#[repr(C)]
+#[derive(Default)]
+struct DevInfo {
+    bustype: u32,
+    vendor: i16,
+    product: i16,
+}
+
+// struct hidraw_report_descriptor
+#[repr(C)]
+struct RawReportDescriptor {
+    size: u32,
+    value: [u8; HID_MAX_DESCRIPTOR_SIZE],
+}
+
+/// Fields of the `uevent` file of a HID device in sysfs.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct HidUevent {
+    name: Option<String>,
+    // e.g. usb-0000:00:14.0-4/input2
+    phys: Option<String>,
+    uniq: Option<String>,
+}
+
+/// Transport talking to `/dev/hidraw*` nodes directly, without hidapi,
+/// libudev or libusb.
+#[derive(Default)]
+pub struct HidrawTransport;
+
+/// An open hidraw node.
+pub struct HidrawConnection {
+    file: File,
+}
+
+impl HidrawTransport {
+    pub fn new() -> Self {
+        Self
+    }
+
+    fn interface_info(&self, node: &str) -> Result<HidInterfaceInfo> {
+        let sys_device = Path::new(SYSFS_HIDRAW_PATH).join(node).join("device");
+        let uevent = fs::read_to_string(sys_device.join("uevent"))
+            .with_context(|| format!("Failed to read uevent of {}", node))?;
+        let uevent = HidUevent::parse(&uevent);
+
+        let path = format!("{}/{}", DEV_PATH, node);
+        let connection = HidrawConnection::open(&path)?;
+        let raw_info = connection.raw_info()?;
+        // Usage of the first top-level collection, the one hidapi reports for the node
+        let (usage_page, usage) = ReportDescriptor::parse(&connection.report_descriptor()?)?
+            .collections
+            .first()
+            .map(|collection| (collection.usage_page, collection.usage))
+            .unwrap_or_default();
+
+        Ok(HidInterfaceInfo {
+            path,
+            vendor_id: raw_info.vendor as u16,
+            product_id: raw_info.product as u16,
+            product_string: uevent.name,
+            serial_number: uevent.uniq,
+            usage_page,
+            usage,
+            interface_number: uevent.phys.as_deref().and_then(interface_number).unwrap_or(-1),
+            bus_type: BusType::from_linux_bus(raw_info.bustype),
+        })
+    }
+}
+
+impl HidTransport for HidrawTransport {
+    fn devices(&mut self) -> Result<Vec<HidInterfaceInfo>> {
+        let entries = fs::read_dir(SYSFS_HIDRAW_PATH)
+            .context("Failed to read hidraw class directory")?;
+
+        let mut devices = Vec::new();
+        for entry in entries {
+            let node = entry?.file_name().to_string_lossy().to_string();
+            match self.interface_info(&node) {
+                Ok(info) => devices.push(info),
+                // Usually a node we lack permissions for
+                Err(e) => debug!("Skipping {}: {:#}", node, e),
+            }
+        }
+
+        Ok(devices)
+    }
+
+    fn open(&self, path: &str) -> Result<Box<dyn HidConnection>> {
+        Ok(Box::new(HidrawConnection::open(path)?))
+    }
+}
+
+impl HidrawConnection {
+    pub fn open(path: &str) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Failed to open {}", path))?;
+
+        Ok(Self { file })
+    }
+
+    fn raw_info(&self) -> Result<DevInfo> {
+        let mut info = DevInfo::default();
+        // SAFETY: HIDIOCGRAWINFO fills a struct hidraw_devinfo
+        let ret = unsafe { libc::ioctl(self.file.as_raw_fd(), HIDIOCGRAWINFO as _, &mut info) };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error()).context("HIDIOCGRAWINFO failed");
+        }
+        Ok(info)
+    }
+}
+
+impl HidConnection for HidrawConnection {
+    fn write(&self, data: &[u8]) -> Result<usize> {
+        (&self.file).write(data)
+            .context("Failed to write to hidraw device")
+    }
+
+    fn read_timeout(&self, buf: &mut [u8], timeout_ms: i32) -> Result<usize> {
+        let mut pollfd = libc::pollfd { fd: self.file.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        // SAFETY: a single valid pollfd
+        let ret = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+        if ret < 0 {
+            let error = std::io::Error::last_os_error();
+            if error.kind() == std::io::ErrorKind::Interrupted {
+                return Ok(0);
+            }
+            return Err(error).context("Failed to poll hidraw device");
+        }
+        if ret == 0 {
+            return Ok(0);
+        }
+        if pollfd.revents & (libc::POLLERR | libc::POLLHUP | libc::POLLNVAL) != 0 {
+            bail!("hidraw device disconnected");
+        }
+
+        (&self.file).read(buf)
+            .context("Failed to read from hidraw device")
+    }
+
+    fn report_descriptor(&self) -> Result<Vec<u8>> {
+        let mut size: libc::c_int = 0;
+        // SAFETY: HIDIOCGRDESCSIZE writes a single int
+        let ret = unsafe { libc::ioctl(self.file.as_raw_fd(), HIDIOCGRDESCSIZE as _, &mut size) };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error()).context("HIDIOCGRDESCSIZE failed");
+        }
+
+        let mut descriptor = RawReportDescriptor {
+            size: (size as u32).min(HID_MAX_DESCRIPTOR_SIZE as u32),
+            value: [0; HID_MAX_DESCRIPTOR_SIZE],
+        };
+        // SAFETY: HIDIOCGRDESC fills at most `size` bytes of `value`
+        let ret = unsafe { libc::ioctl(self.file.as_raw_fd(), HIDIOCGRDESC as _, &mut descriptor) };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error()).context("HIDIOCGRDESC failed");
+        }
+
+        Ok(descriptor.value[..descriptor.size as usize].to_vec())
+    }
+}
+
+impl HidUevent {
+    fn parse(content: &str) -> Self {
+        let mut uevent = HidUevent::default();
+
+        for line in content.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            match key {
+                "HID_NAME" => uevent.name = non_empty(value),
+                "HID_PHYS" => uevent.phys = non_empty(value),
+                "HID_UNIQ" => uevent.uniq = non_empty(value),
+                _ => {}
+            }
+        }
+
+        uevent
+    }
+}
+
+/// Resolve the canonical sysfs directory of the USB device a hidraw node
+/// belongs to, for comparison with the `sys_path` of a `USBManager`.
+///
+/// # Returns
+/// * `Some(path)` - e.g. `/sys/devices/pci0000:00/0000:00:14.0/usb1/1-4`
+/// * `None` - The node does not exist or does not sit on a USB device
+pub fn usb_device_path(hidraw_path: &str) -> Option<PathBuf> {
+    let node = Path::new(hidraw_path).file_name()?;
+    // .../1-4/1-4:1.2/0003:046D:C548.0004, the USB interface is the parent
+    // of the HID device and the USB device is the parent of the interface
+    let hid_device = fs::canonicalize(Path::new(SYSFS_HIDRAW_PATH).join(node).join("device")).ok()?;
+    let usb_device = hid_device.parent()?.parent()?;
+    usb_device.join("idVendor").exists().then(|| usb_device.to_path_buf())
+}
+
+/// Interface number from the `/inputN` suffix of the HID_PHYS of a USB device.
+fn interface_number(phys: &str) -> Option<i32> {
+    phys.rsplit_once("/input")?.1.parse().ok()
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+const fn ior(nr: u32, size: usize) -> u32 {
+    const IOC_READ: u32 = 2;
+    (IOC_READ << 30) | ((size as u32) << 16) | ((b'H' as u32) << 8) | nr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ioctl_numbers() {
+        assert_eq!(HIDIOCGRDESCSIZE, 0x8004_4801);
+        assert_eq!(HIDIOCGRDESC, 0x9004_4802);
+        assert_eq!(HIDIOCGRAWINFO, 0x8008_4803);
+    }
+
+    #[test]
+    fn test_parse_uevent() {
+        let content = "DRIVER=logitech-djreceiver\n\
+                       HID_ID=0003:0000046D:0000C548\n\
+                       HID_NAME=Logitech USB Receiver\n\
+                       HID_PHYS=usb-0000:00:14.0-4/input2\n\
+                       HID_UNIQ=\n\
+                       MODALIAS=hid:b0003g0102v0000046Dp0000C548\n";
+        assert_eq!(HidUevent::parse(content), HidUevent {
+            name: Some("Logitech USB Receiver".to_string()),
+            phys: Some("usb-0000:00:14.0-4/input2".to_string()),
+            uniq: None,
+        });
+    }
+
+    #[test]
+    fn test_interface_number_from_phys() {
+        assert_eq!(interface_number("usb-0000:00:14.0-4/input2"), Some(2));
+        assert_eq!(interface_number("usb-0000:00:14.0-4.1/input0"), Some(0));
+        // Bluetooth devices have the adapter address as phys
+        assert_eq!(interface_number("9c:b6:d0:12:34:56"), None);
+    }
+}
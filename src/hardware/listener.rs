@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use log::{debug, warn};
+use tokio::sync::mpsc;
+
+use super::hid::{BatteryStatus, UnifiedBatteryCapabilities, VoltageCurve,
+                 FEATURE_BATTERY_STATUS, FEATURE_BATTERY_VOLTAGE, FEATURE_UNIFIED_BATTERY};
+use super::hidpp::{HidppMessage, ReportType, VERY_LONG_REPORT_LEN};
+use super::transport::HidConnection;
+
+const CHANNEL_CAPACITY: usize = 16;
+// Bounds how long the task lingers after the receiver is dropped
+const READ_TIMEOUT_MS: i32 = 1000;
+
+// HID++ 1.0 device connection notification sent by receivers
+const CONNECTION_SUB_ID: u8 = 0x41;
+const CONNECTION_LINK_NOT_ESTABLISHED: u8 = 1 << 6;
+// Battery events are function 0 with software ID 0
+const BATTERY_EVENT_FUNCTION_BYTE: u8 = 0x00;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceEvent {
+    Battery {
+        device_index: u8,
+        status: BatteryStatus,
+    },
+    Connection {
+        device_index: u8,
+        wireless_product_id: u16,
+        connected: bool,
+    },
+}
+
+struct BatteryFeature {
+    id: u16,
+    capabilities: Option<UnifiedBatteryCapabilities>,
+}
+
+/// Turns unsolicited HID++ frames of one hidraw node into `DeviceEvent`s.
+pub struct NotificationDecoder {
+    // (device index, feature index) -> battery feature living there
+    battery_features: HashMap<(u8, u8), BatteryFeature>,
+    voltage_curve: VoltageCurve,
+}
+
+impl NotificationDecoder {
+    pub fn new(voltage_curve: VoltageCurve) -> Self {
+        Self { battery_features: HashMap::new(), voltage_curve }
+    }
+
+    pub fn add_battery_feature(&mut self, device_index: u8, feature_index: u8, feature_id: u16, capabilities: Option<UnifiedBatteryCapabilities>) {
+        self.battery_features.insert((device_index, feature_index), BatteryFeature { id: feature_id, capabilities });
+    }
+
+    pub fn decode(&self, frame: &HidppMessage) -> Option<DeviceEvent> {
+        if frame.report_type == ReportType::Short && frame.feature_index == CONNECTION_SUB_ID {
+            // Address byte holds the protocol, data starts with the link flags
+            // followed by the wireless product ID (little endian)
+            return Some(DeviceEvent::Connection {
+                device_index: frame.device_index,
+                wireless_product_id: u16::from_le_bytes([frame.params[1], frame.params[2]]),
+                connected: frame.params[0] & CONNECTION_LINK_NOT_ESTABLISHED == 0,
+            });
+        }
+
+        if frame.function_byte != BATTERY_EVENT_FUNCTION_BYTE {
+            return None;
+        }
+        let feature = self.battery_features.get(&(frame.device_index, frame.feature_index))?;
+
+        // Battery events carry the same payload as the matching get-status reply
+        let status = match feature.id {
+            FEATURE_UNIFIED_BATTERY => {
+                let capabilities = feature.capabilities.as_ref()?;
+                BatteryStatus::from_unified_battery(capabilities, &frame.params)
+            }
+            FEATURE_BATTERY_STATUS => BatteryStatus::from_battery_status(&frame.params),
+            FEATURE_BATTERY_VOLTAGE => Ok(BatteryStatus::from_battery_voltage(&self.voltage_curve, &frame.params)),
+            _ => return None,
+        };
+
+        match status {
+            Ok(status) => Some(DeviceEvent::Battery { device_index: frame.device_index, status }),
+            Err(e) => {
+                warn!("Failed to decode battery notification: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// Read notifications off `device` on a blocking task and forward the decoded
+/// events until the returned receiver is dropped or reading fails.
+pub fn spawn(device: Box<dyn HidConnection>, path: String, decoder: NotificationDecoder) -> mpsc::Receiver<DeviceEvent> {
+    let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+
+    tokio::task::spawn_blocking(move || {
+        let mut buf = [0u8; VERY_LONG_REPORT_LEN];
+        while !sender.is_closed() {
+            let bytes_read = match device.read_timeout(&mut buf, READ_TIMEOUT_MS) {
+                Ok(bytes_read) => bytes_read,
+                Err(e) => {
+                    warn!("Notification listener on {} stopped: {}", path, e);
+                    break;
+                }
+            };
+            if bytes_read == 0 {
+                continue;
+            }
+
+            let Ok(frame) = HidppMessage::decode(&buf[..bytes_read]) else {
+                continue;
+            };
+            if let Some(event) = decoder.decode(&frame) {
+                debug!("Notification from {}: {:?}", path, event);
+                if sender.blocking_send(event).is_err() {
+                    break;
+                }
+            }
+        }
+        debug!("Notification listener on {} finished", path);
+    });
+
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::hid::{BatteryLevel, ChargingStatus};
+
+    fn frame(report_type: ReportType, device_index: u8, feature_index: u8, function_byte: u8, params: &[u8]) -> HidppMessage {
+        let mut padded = params.to_vec();
+        padded.resize(report_type.max_params(), 0);
+        HidppMessage { report_type, device_index, feature_index, function_byte, params: padded }
+    }
+
+    fn decoder() -> NotificationDecoder {
+        let mut decoder = NotificationDecoder::new(VoltageCurve::default());
+        let capabilities = UnifiedBatteryCapabilities { supported_levels: 0x0F, rechargeable: true, state_of_charge: true };
+        decoder.add_battery_feature(0xFF, 0x05, FEATURE_UNIFIED_BATTERY, Some(capabilities));
+        decoder.add_battery_feature(0x02, 0x07, FEATURE_BATTERY_STATUS, None);
+        decoder
+    }
+
+    #[test]
+    fn test_decode_unified_battery_event() {
+        let event = decoder().decode(&frame(ReportType::Long, 0xFF, 0x05, 0x00, &[81, 0x04, 0x03, 0x01]));
+        assert_eq!(event, Some(DeviceEvent::Battery {
+            device_index: 0xFF,
+            status: BatteryStatus {
+                percentage: Some(81),
+                level: BatteryLevel::Good,
+                charging: ChargingStatus::Full,
+                external_power: true,
+                voltage_mv: None,
+            },
+        }));
+    }
+
+    #[test]
+    fn test_decode_battery_status_event_behind_receiver() {
+        let event = decoder().decode(&frame(ReportType::Long, 0x02, 0x07, 0x00, &[30, 20, 1]));
+        let Some(DeviceEvent::Battery { device_index, status }) = event else {
+            panic!("expected battery event, got {:?}", event);
+        };
+        assert_eq!(device_index, 0x02);
+        assert_eq!(status.percentage, Some(30));
+        assert_eq!(status.charging, ChargingStatus::Charging);
+    }
+
+    #[test]
+    fn test_decode_ignores_replies_and_other_features() {
+        // Reply to our own get_status (software ID 1)
+        assert_eq!(decoder().decode(&frame(ReportType::Long, 0xFF, 0x05, 0x11, &[81, 0x04, 0x00, 0x00])), None);
+        // Notification of a feature we do not track
+        assert_eq!(decoder().decode(&frame(ReportType::Long, 0xFF, 0x09, 0x00, &[0x01])), None);
+    }
+
+    #[test]
+    fn test_decode_connection_event() {
+        let connected = decoder().decode(&frame(ReportType::Short, 0x01, CONNECTION_SUB_ID, 0x04, &[0x02, 0x69, 0xB3]));
+        assert_eq!(connected, Some(DeviceEvent::Connection {
+            device_index: 0x01,
+            wireless_product_id: 0xB369,
+            connected: true,
+        }));
+
+        let disconnected = decoder().decode(&frame(ReportType::Short, 0x01, CONNECTION_SUB_ID, 0x04, &[0x42, 0x69, 0xB3]));
+        assert!(matches!(disconnected, Some(DeviceEvent::Connection { connected: false, .. })));
+    }
+
+    #[tokio::test]
+    async fn test_listener_forwards_mock_notifications() {
+        use crate::hardware::hid::{LogitechManager, LOGITECH_VENDOR_ID};
+        use crate::hardware::mock::{MockBattery, MockDevice, MockTransport};
+
+        let device = MockDevice::new(MockBattery::discharging(42));
+        let mut transport = MockTransport::new();
+        transport.add_device(LOGITECH_VENDOR_ID, 0xB369, "/dev/hidraw0", device.clone());
+        let mut manager = LogitechManager::with_transport(Box::new(transport));
+
+        let mut events = manager.start_listener(LOGITECH_VENDOR_ID, 0xB369).unwrap().unwrap();
+        device.set_battery(MockBattery::charging(43));
+        device.notify(&device.battery_notification(0xFF));
+
+        match events.recv().await {
+            Some(DeviceEvent::Battery { device_index: 0xFF, status }) => {
+                assert_eq!(status.percentage, Some(43));
+                assert!(status.charging.is_charging());
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        device.unplug();
+        assert_eq!(events.recv().await, None);
+    }
+}
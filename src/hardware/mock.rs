@@ -0,0 +1,366 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::{anyhow, bail, Result};
+
+use super::hid::{BatteryLevel, ChargingStatus, FEATURE_FEATURE_SET, FEATURE_ROOT, FEATURE_UNIFIED_BATTERY};
+use super::descriptor::ReportDescriptor;
+use super::hidpp::{HidppMessage, ReportType};
+use super::transport::{BusType, HidConnection, HidInterfaceInfo, HidTransport};
+
+// Vendor collection on usage page 0xFF00 with short (0x10) and long (0x11)
+// input and output reports, as found on receivers and wired devices
+const REPORT_DESCRIPTOR: [u8; 39] = [
+    0x06, 0x00, 0xFF, // Usage Page (0xFF00)
+    0x09, 0x01,       // Usage (0x01)
+    0xA1, 0x01,       // Collection (Application)
+    0x85, 0x10,       //   Report ID (0x10)
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x06,       //   Report Count (6)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x09, 0x01,       //   Usage (0x01)
+    0x81, 0x00,       //   Input
+    0x09, 0x01,       //   Usage (0x01)
+    0x91, 0x00,       //   Output
+    0x85, 0x11,       //   Report ID (0x11)
+    0x95, 0x13,       //   Report Count (19)
+    0x09, 0x02,       //   Usage (0x02)
+    0x81, 0x00,       //   Input
+    0x09, 0x02,       //   Usage (0x02)
+    0x91, 0x00,       //   Output
+    0xC0,             // End Collection
+];
+
+// HID++ 2.0 error codes answered by the mock
+pub const ERROR_INVALID_ARGUMENT: u8 = 0x02;
+pub const ERROR_INVALID_FEATURE_INDEX: u8 = 0x06;
+pub const ERROR_INVALID_FUNCTION_ID: u8 = 0x07;
+const ERROR_FEATURE_INDEX: u8 = 0xFF;
+
+// Bounds how long an empty read blocks, keeps timeouts in tests short
+const IDLE_READ_MS: u64 = 5;
+
+/// Battery state a `MockDevice` reports through UnifiedBattery (0x1004).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MockBattery {
+    pub percentage: u8,
+    pub level: BatteryLevel,
+    pub charging: ChargingStatus,
+    pub external_power: bool,
+}
+
+#[derive(Default)]
+struct DeviceState {
+    report_descriptor: Vec<u8>,
+    features: Vec<u16>,
+    battery: Option<MockBattery>,
+    // Whether the device gets power, it discharges whenever this says no
+    powered: Option<Box<dyn Fn() -> bool + Send>>,
+    // Error code answered to every request on a feature index
+    errors: HashMap<u8, u8>,
+    responding: bool,
+    unplugged: bool,
+    requests: Vec<HidppMessage>,
+    // Pending input reports, one queue per open connection
+    queues: Vec<VecDeque<Vec<u8>>>,
+}
+
+/// Scriptable HID++ 2.0 device implementing IRoot, FeatureSet and
+/// UnifiedBattery (0x1004), answering on any device index.
+///
+/// Clones share their state, so a test can keep one around to change the
+/// battery, inject errors or push notifications while the code under test
+/// talks to the device through a `MockTransport`.
+#[derive(Clone)]
+pub struct MockDevice {
+    state: Arc<Mutex<DeviceState>>,
+}
+
+/// In-memory `HidTransport` serving a fixed set of `MockDevice` interfaces.
+#[derive(Default)]
+pub struct MockTransport {
+    interfaces: Vec<(HidInterfaceInfo, MockDevice)>,
+}
+
+struct MockConnection {
+    state: Arc<Mutex<DeviceState>>,
+    queue: usize,
+}
+
+impl MockBattery {
+    pub fn discharging(percentage: u8) -> Self {
+        Self {
+            percentage,
+            level: BatteryLevel::from_percentage(percentage),
+            charging: ChargingStatus::Discharging,
+            external_power: false,
+        }
+    }
+
+    pub fn charging(percentage: u8) -> Self {
+        Self {
+            percentage,
+            level: BatteryLevel::from_percentage(percentage),
+            charging: ChargingStatus::Charging,
+            external_power: true,
+        }
+    }
+
+    fn status_params(&self) -> Vec<u8> {
+        let level = match self.level {
+            BatteryLevel::Critical => 1 << 0,
+            BatteryLevel::Low => 1 << 1,
+            BatteryLevel::Good => 1 << 2,
+            BatteryLevel::Full => 1 << 3,
+            BatteryLevel::Unknown => 0,
+        };
+        let charging = match self.charging {
+            ChargingStatus::Discharging => 0,
+            ChargingStatus::Charging => 1,
+            ChargingStatus::SlowCharging => 2,
+            ChargingStatus::Full => 3,
+            ChargingStatus::Error => 4,
+        };
+        vec![self.percentage, level, charging, self.external_power as u8]
+    }
+}
+
+impl MockDevice {
+    /// A device with a rechargeable battery reporting `battery`.
+    pub fn new(battery: MockBattery) -> Self {
+        Self::with_features(&[FEATURE_ROOT, FEATURE_FEATURE_SET, FEATURE_UNIFIED_BATTERY], Some(battery))
+    }
+
+    /// A device exposing `features` in index order, IRoot must come first.
+    ///
+    /// Features other than IRoot, FeatureSet and UnifiedBattery are listed
+    /// but answer every function with InvalidFunctionId.
+    pub fn with_features(features: &[u16], battery: Option<MockBattery>) -> Self {
+        let state = DeviceState {
+            report_descriptor: REPORT_DESCRIPTOR.to_vec(),
+            features: features.to_vec(),
+            battery,
+            responding: true,
+            ..DeviceState::default()
+        };
+        Self { state: Arc::new(Mutex::new(state)) }
+    }
+
+    /// Replace the report descriptor, e.g. to pose as a keyboard interface.
+    pub fn with_report_descriptor(self, descriptor: &[u8]) -> Self {
+        self.state.lock().unwrap().report_descriptor = descriptor.to_vec();
+        self
+    }
+
+    pub fn set_battery(&self, battery: MockBattery) {
+        self.state.lock().unwrap().battery = Some(battery);
+    }
+
+    /// Take power from wherever `powered` says, e.g. a port the code under
+    /// test switches. A charging battery discharges while it returns false.
+    pub fn set_powered(&self, powered: impl Fn() -> bool + Send + 'static) {
+        self.state.lock().unwrap().powered = Some(Box::new(powered));
+    }
+
+    /// Answer every request on `feature_index` with HID++ 2.0 error `code`.
+    pub fn fail_feature(&self, feature_index: u8, code: u8) {
+        self.state.lock().unwrap().errors.insert(feature_index, code);
+    }
+
+    /// Stop answering requests, as a device that went to sleep.
+    pub fn set_responding(&self, responding: bool) {
+        self.state.lock().unwrap().responding = responding;
+    }
+
+    /// Fail every read and write and drop out of enumeration.
+    pub fn unplug(&self) {
+        self.state.lock().unwrap().unplugged = true;
+    }
+
+    /// Requests received so far, over all connections.
+    pub fn requests(&self) -> Vec<HidppMessage> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Deliver a device-initiated report to every open connection.
+    pub fn notify(&self, frame: &HidppMessage) {
+        let report = frame.encode().expect("valid notification frame");
+        for queue in &mut self.state.lock().unwrap().queues {
+            queue.push_back(report.clone());
+        }
+    }
+
+    /// UnifiedBattery status notification (event 0x00) for `device_index`.
+    pub fn battery_notification(&self, device_index: u8) -> HidppMessage {
+        let state = self.state.lock().unwrap();
+        let feature_index = state.features.iter().position(|id| *id == FEATURE_UNIFIED_BATTERY)
+            .expect("device has no UnifiedBattery feature") as u8;
+        let battery = state.battery().expect("device has no battery");
+        long_frame(device_index, feature_index, 0x00, &battery.status_params())
+    }
+
+    fn is_unplugged(&self) -> bool {
+        self.state.lock().unwrap().unplugged
+    }
+
+    fn connect(&self) -> MockConnection {
+        let mut state = self.state.lock().unwrap();
+        state.queues.push(VecDeque::new());
+        MockConnection { state: self.state.clone(), queue: state.queues.len() - 1 }
+    }
+}
+
+impl DeviceState {
+    fn battery(&self) -> Option<MockBattery> {
+        let battery = self.battery?;
+        match &self.powered {
+            Some(powered) if !powered() => Some(MockBattery::discharging(battery.percentage)),
+            _ => Some(battery),
+        }
+    }
+
+    fn reply(&self, request: &HidppMessage) -> HidppMessage {
+        if let Some(code) = self.errors.get(&request.feature_index) {
+            return error_frame(request, *code);
+        }
+        let Some(feature_id) = self.features.get(request.feature_index as usize) else {
+            return error_frame(request, ERROR_INVALID_FEATURE_INDEX);
+        };
+
+        let params = &request.params;
+        let result = match (*feature_id, request.function_byte >> 4) {
+            // IRoot getFeature: index, type, version; index 0 when unsupported
+            (FEATURE_ROOT, 0x00) => {
+                let id = u16::from_be_bytes([params[0], params[1]]);
+                let index = self.features.iter().position(|f| *f == id).unwrap_or(0);
+                Ok(vec![index as u8, 0, 0])
+            }
+            // IRoot ping: protocol version 4.2, echoing the ping data
+            (FEATURE_ROOT, 0x01) => Ok(vec![4, 2, params[2]]),
+            // FeatureSet getCount, excluding IRoot
+            (FEATURE_FEATURE_SET, 0x00) => Ok(vec![self.features.len() as u8 - 1]),
+            // FeatureSet getFeatureID: ID, type, version
+            (FEATURE_FEATURE_SET, 0x01) => match self.features.get(params[0] as usize) {
+                Some(id) => Ok(vec![(id >> 8) as u8, *id as u8, 0, 0]),
+                None => Err(ERROR_INVALID_ARGUMENT),
+            },
+            // UnifiedBattery getCapabilities: all four levels, rechargeable with state of charge
+            (FEATURE_UNIFIED_BATTERY, 0x00) => Ok(vec![0x0F, 0x03]),
+            (FEATURE_UNIFIED_BATTERY, 0x01) => match self.battery() {
+                Some(battery) => Ok(battery.status_params()),
+                None => Err(ERROR_INVALID_ARGUMENT),
+            },
+            _ => Err(ERROR_INVALID_FUNCTION_ID),
+        };
+
+        match result {
+            // Devices answer short requests with long replies as well
+            Ok(params) => long_frame(request.device_index, request.feature_index, request.function_byte, &params),
+            Err(code) => error_frame(request, code),
+        }
+    }
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Expose `device` as a USB interface with the given IDs and hidraw path,
+    /// enumerated with the usage of its first top-level collection.
+    pub fn add_device(&mut self, vendor_id: u16, product_id: u16, path: &str, device: MockDevice) {
+        self.add_interface(vendor_id, product_id, path, BusType::Usb, device);
+    }
+
+    /// Expose `device` as a Bluetooth interface, see `add_device`.
+    pub fn add_bluetooth_device(&mut self, vendor_id: u16, product_id: u16, path: &str, device: MockDevice) {
+        self.add_interface(vendor_id, product_id, path, BusType::Bluetooth, device);
+    }
+
+    fn add_interface(&mut self, vendor_id: u16, product_id: u16, path: &str, bus_type: BusType, device: MockDevice) {
+        let descriptor = ReportDescriptor::parse(&device.state.lock().unwrap().report_descriptor)
+            .expect("valid mock report descriptor");
+        let collection = descriptor.collections.first().expect("mock report descriptor without collection");
+
+        let info = HidInterfaceInfo {
+            path: path.to_string(),
+            vendor_id,
+            product_id,
+            product_string: Some("Mock HID++ device".to_string()),
+            serial_number: None,
+            usage_page: collection.usage_page,
+            usage: collection.usage,
+            interface_number: self.interfaces.len() as i32,
+            bus_type,
+        };
+        self.interfaces.push((info, device));
+    }
+}
+
+impl HidTransport for MockTransport {
+    fn devices(&mut self) -> Result<Vec<HidInterfaceInfo>> {
+        Ok(self.interfaces
+            .iter()
+            .filter(|(_, device)| !device.is_unplugged())
+            .map(|(info, _)| info.clone())
+            .collect())
+    }
+
+    fn open(&self, path: &str) -> Result<Box<dyn HidConnection>> {
+        let (_, device) = self.interfaces
+            .iter()
+            .find(|(info, device)| info.path == path && !device.is_unplugged())
+            .ok_or_else(|| anyhow!("No mock HID device at {}", path))?;
+
+        Ok(Box::new(device.connect()))
+    }
+}
+
+impl HidConnection for MockConnection {
+    fn write(&self, data: &[u8]) -> Result<usize> {
+        let request = HidppMessage::decode(data)?;
+        let mut state = self.state.lock().unwrap();
+        if state.unplugged {
+            bail!("Mock HID device disconnected");
+        }
+
+        if state.responding {
+            let reply = state.reply(&request).encode()?;
+            state.queues[self.queue].push_back(reply);
+        }
+        state.requests.push(request);
+        Ok(data.len())
+    }
+
+    fn read_timeout(&self, buf: &mut [u8], timeout_ms: i32) -> Result<usize> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.unplugged {
+                bail!("Mock HID device disconnected");
+            }
+            if let Some(report) = state.queues[self.queue].pop_front() {
+                let len = report.len().min(buf.len());
+                buf[..len].copy_from_slice(&report[..len]);
+                return Ok(len);
+            }
+        }
+
+        std::thread::sleep(Duration::from_millis(IDLE_READ_MS.min(timeout_ms.max(0) as u64)));
+        Ok(0)
+    }
+
+    fn report_descriptor(&self) -> Result<Vec<u8>> {
+        Ok(self.state.lock().unwrap().report_descriptor.clone())
+    }
+}
+
+fn long_frame(device_index: u8, feature_index: u8, function_byte: u8, params: &[u8]) -> HidppMessage {
+    let mut params = params.to_vec();
+    params.resize(ReportType::Long.max_params(), 0);
+    HidppMessage { report_type: ReportType::Long, device_index, feature_index, function_byte, params }
+}
+
+fn error_frame(request: &HidppMessage, code: u8) -> HidppMessage {
+    long_frame(request.device_index, ERROR_FEATURE_INDEX, request.feature_index, &[request.function_byte, code])
+}
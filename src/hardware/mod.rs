@@ -1,7 +1,20 @@
 pub mod usb;
+pub mod descriptor;
 pub mod hid;
+pub mod hidpp;
+pub mod hidraw;
+pub mod listener;
+pub mod port;
 pub mod power;
+pub mod power_supply;
+pub mod source;
+pub mod transport;
+pub mod usbfs;
+#[cfg(test)]
+pub mod mock;
 
 pub use usb::USBDeviceManager;
-pub use hid::LogitechManager;
+pub use hid::{LogitechManager, VoltageCurve};
 pub use power::PowerManager;
+pub use power_supply::PowerSupplyManager;
+pub use source::BatterySource;
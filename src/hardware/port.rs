@@ -0,0 +1,230 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, bail, Context, Result};
+use log::debug;
+
+use super::usbfs::UsbfsDevice;
+
+// USB hub class requests and port features from the USB 2.0 spec, 11.24
+const RT_HUB_IN: u8 = 0xA0;
+const RT_PORT_OUT: u8 = 0x23;
+const RT_PORT_IN: u8 = 0xA3;
+const GET_STATUS: u8 = 0x00;
+const CLEAR_FEATURE: u8 = 0x01;
+const SET_FEATURE: u8 = 0x03;
+const GET_DESCRIPTOR: u8 = 0x06;
+const PORT_POWER: u16 = 8;
+
+// Hub descriptor types (USB 2.0 spec, 11.23.2.1 and USB 3.x spec, 10.15.2.1)
+const HUB_DESCRIPTOR: u8 = 0x29;
+const SS_HUB_DESCRIPTOR: u8 = 0x2A;
+
+// wPortStatus power bit, moved for SuperSpeed hubs (USB 3.x spec, 10.16.2.6.1)
+const PORT_STAT_POWER: u16 = 0x0100;
+const PORT_STAT_POWER_SS: u16 = 0x0200;
+
+/// The downstream port of a hub a USB device is plugged into.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsbPort {
+    // e.g. /sys/bus/usb/devices/1-4 or /sys/bus/usb/devices/usb1
+    hub_sys_path: PathBuf,
+    port: u8,
+}
+
+/// How to switch port power.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortSwitch {
+    /// The kernel's `<hub>-port<N>/disable` attribute
+    DisableAttribute,
+    /// Set/ClearPortFeature(PORT_POWER) sent to the hub through usbfs
+    HubRequest,
+}
+
+impl UsbPort {
+    /// Port of the device at `sys_path`, e.g. port 2 of hub `1-4` for
+    /// `/sys/bus/usb/devices/1-4.2`. Only the name is looked at, so this also
+    /// works for devices that went away because their port is powered off.
+    pub fn for_device(sys_path: &str) -> Result<Self> {
+        let path = Path::new(sys_path);
+        let name = path.file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("No USB device name in {}", sys_path))?;
+        let devices_dir = path.parent().unwrap_or(Path::new("/"));
+
+        // Devices are named <bus>-<port>[.<port>...], the root hub is usb<bus>,
+        // interfaces <device>:<config>.<interface>
+        if name.contains(':') {
+            bail!("{} is a USB interface, not a device", name);
+        }
+        let (hub_name, port) = match name.rsplit_once('.') {
+            Some((hub, port)) => (hub.to_string(), port),
+            None => match name.split_once('-') {
+                Some((bus, port)) => (format!("usb{}", bus), port),
+                None => bail!("{} is not a USB device behind a hub", name),
+            },
+        };
+        let port = port.parse()
+            .with_context(|| format!("Invalid port number in USB device name {}", name))?;
+
+        Ok(Self { hub_sys_path: devices_dir.join(hub_name), port })
+    }
+
+    pub fn hub_sys_path(&self) -> &Path {
+        &self.hub_sys_path
+    }
+
+    pub fn port(&self) -> u8 {
+        self.port
+    }
+
+    /// Class descriptor of the hub, read with a GetHubDescriptor request.
+    pub fn read_hub_descriptor(&self) -> Result<Vec<u8>> {
+        let descriptor_type = if self.is_super_speed_hub() { SS_HUB_DESCRIPTOR } else { HUB_DESCRIPTOR };
+        let mut descriptor = [0u8; 64];
+        let len = self.open_hub()?
+            .control_in(RT_HUB_IN, GET_DESCRIPTOR, (descriptor_type as u16) << 8, 0, &mut descriptor)
+            .with_context(|| format!("Failed to read hub descriptor of {}", self.hub_name()))?;
+        Ok(descriptor[..len].to_vec())
+    }
+
+    /// Switch the power of the port with `switch`.
+    pub fn set_power(&self, on: bool, switch: PortSwitch) -> Result<()> {
+        match switch {
+            PortSwitch::DisableAttribute => {
+                let disable_path = self.disable_path()
+                    .ok_or_else(|| anyhow!("No disable attribute for {}", self))?;
+                fs::write(&disable_path, if on { "0" } else { "1" })
+                    .with_context(|| format!("Failed to write {}", disable_path.display()))
+            }
+            PortSwitch::HubRequest => {
+                let request = if on { SET_FEATURE } else { CLEAR_FEATURE };
+                self.open_hub()?
+                    .control_out(RT_PORT_OUT, request, PORT_POWER, self.port as u16)
+                    .with_context(|| format!("Failed to switch power of {}", self))
+            }
+        }
+    }
+
+    /// Whether the port currently supplies power.
+    pub fn is_powered(&self) -> Result<bool> {
+        if let Some(disable_path) = self.disable_path() {
+            let disabled = fs::read_to_string(&disable_path)
+                .with_context(|| format!("Failed to read {}", disable_path.display()))?;
+            return Ok(disabled.trim() == "0");
+        }
+
+        // GetPortStatus: wPortStatus, wPortChange
+        let mut status = [0u8; 4];
+        let len = self.open_hub()?
+            .control_in(RT_PORT_IN, GET_STATUS, 0, self.port as u16, &mut status)
+            .with_context(|| format!("Failed to get status of {}", self))?;
+        if len < 2 {
+            bail!("Short port status from {}", self);
+        }
+        let port_status = u16::from_le_bytes([status[0], status[1]]);
+        Ok(port_status & self.power_status_bit() != 0)
+    }
+
+    /// `<hub>-port<N>/disable` under whichever hub interface carries the port.
+    fn disable_path(&self) -> Option<PathBuf> {
+        let port_name = format!("{}-port{}", self.hub_name(), self.port);
+        fs::read_dir(&self.hub_sys_path).ok()?
+            .flatten()
+            .map(|entry| entry.path().join(&port_name).join("disable"))
+            .find(|path| path.exists())
+    }
+
+    fn open_hub(&self) -> Result<UsbfsDevice> {
+        let bus = read_number(&self.hub_sys_path.join("busnum"))?;
+        let device = read_number(&self.hub_sys_path.join("devnum"))?;
+        debug!("Talking to hub {} at bus {} device {}", self.hub_name(), bus, device);
+        UsbfsDevice::open(bus, device)
+    }
+
+    fn power_status_bit(&self) -> u16 {
+        if self.is_super_speed_hub() { PORT_STAT_POWER_SS } else { PORT_STAT_POWER }
+    }
+
+    fn is_super_speed_hub(&self) -> bool {
+        // Speed in Mbit/s, SuperSpeed from 5000 on
+        fs::read_to_string(self.hub_sys_path.join("speed"))
+            .ok()
+            .and_then(|speed| speed.trim().parse::<u32>().ok())
+            .is_some_and(|speed| speed >= 5000)
+    }
+
+    pub fn hub_name(&self) -> &str {
+        self.hub_sys_path.file_name().and_then(|name| name.to_str()).unwrap_or("")
+    }
+}
+
+fn read_number(path: &Path) -> Result<u8> {
+    fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid number in {}", path.display()))
+}
+
+impl fmt::Display for UsbPort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-port{}", self.hub_name(), self.port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_port_for_device() {
+        let port = UsbPort::for_device("/sys/bus/usb/devices/1-4.2").unwrap();
+        assert_eq!(port.hub_sys_path(), Path::new("/sys/bus/usb/devices/1-4"));
+        assert_eq!(port.port(), 2);
+        assert_eq!(port.to_string(), "1-4-port2");
+
+        let port = UsbPort::for_device("/sys/bus/usb/devices/3-1.4.3").unwrap();
+        assert_eq!(port.to_string(), "3-1.4-port3");
+
+        // Plugged straight into the root hub
+        let port = UsbPort::for_device("/sys/bus/usb/devices/1-4").unwrap();
+        assert_eq!(port.hub_sys_path(), Path::new("/sys/bus/usb/devices/usb1"));
+        assert_eq!(port.to_string(), "usb1-port4");
+
+        assert!(UsbPort::for_device("/sys/bus/usb/devices/usb1").is_err());
+        assert!(UsbPort::for_device("/sys/bus/usb/devices/1-4:1.0").is_err());
+    }
+
+    #[test]
+    fn test_switch_power_with_disable_attribute() {
+        let dir = tempfile::tempdir().unwrap();
+        let devices = dir.path();
+        let port_dir = devices.join("usb1").join("1-0:1.0").join("usb1-port4");
+        fs::create_dir_all(&port_dir).unwrap();
+        fs::write(port_dir.join("disable"), "0\n").unwrap();
+
+        let port = UsbPort::for_device(&devices.join("1-4").to_string_lossy()).unwrap();
+        assert!(port.is_powered().unwrap());
+
+        port.set_power(false, PortSwitch::DisableAttribute).unwrap();
+        assert_eq!(fs::read_to_string(port_dir.join("disable")).unwrap(), "1");
+        assert!(!port.is_powered().unwrap());
+
+        port.set_power(true, PortSwitch::DisableAttribute).unwrap();
+        assert!(port.is_powered().unwrap());
+    }
+
+    #[test]
+    fn test_hub_request_needs_usbfs_node() {
+        let dir = tempfile::tempdir().unwrap();
+        let devices = dir.path();
+        fs::create_dir_all(devices.join("1-4")).unwrap();
+
+        // No disable attribute and no bus/device number to find the hub with
+        let port = UsbPort::for_device(&devices.join("1-4.2").to_string_lossy()).unwrap();
+        assert!(port.set_power(false, PortSwitch::DisableAttribute).is_err());
+        let err = port.set_power(false, PortSwitch::HubRequest).unwrap_err();
+        assert!(format!("{:#}", err).contains("busnum"));
+    }
+}
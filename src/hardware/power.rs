@@ -1,59 +1,317 @@
-use anyhow::{Context, Result};
 use std::fs;
-use log::{debug, warn};
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
 
+use crate::config::ChargingStrategy;
+use super::port::{PortSwitch, UsbPort};
+
+// Driver of USB devices as a whole, as opposed to their interfaces
+const USB_DEVICE_DRIVER: &str = "/sys/bus/usb/drivers/usb";
+
+// Keeps a device from runtime suspending, whatever its autosuspend delay
+const PM_CONTROL_ON: &str = "on";
+
+/// A charging strategy applied to a device, with what it takes to undo it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum ChargingAction {
+    // Settings from before, the delay is unknown when nothing was recorded
+    RuntimeSuspend { sys_path: String, control: String, autosuspend_delay_ms: Option<String> },
+    Deauthorize { sys_path: String },
+    Unbind { sys_path: String, driver: PathBuf },
+    PortDisable { sys_path: String },
+    HubPowerOff { sys_path: String },
+}
+
+/// Stops and resumes charging of USB devices with the configured strategies.
 pub struct PowerManager;
 
 impl PowerManager {
     pub fn new() -> Self {
         Self
     }
-    
-    pub fn set_charging_enabled(&self, sys_path: &str) -> Result<()> {
-        let autosuspend_path = format!("{}/power/autosuspend", sys_path);
-        let control_path = format!("{}/power/control", sys_path);
-        self.enable_charging(&autosuspend_path, &control_path)
-    }
-
-    pub fn set_charging_disabled(&self, sys_path: &str) -> Result<()> {
-        let autosuspend_path = format!("{}/power/autosuspend", sys_path);
-        let control_path = format!("{}/power/control", sys_path);
-        self.disable_charging(&autosuspend_path, &control_path)
-    }
-    
-    fn enable_charging(&self, autosuspend_path: &str, control_path: &str) -> Result<()> {
-        // Enable autosuspend and set control to auto for normal charging
-        if let Err(e) = fs::write(autosuspend_path, "2") {
-            warn!("Failed to write autosuspend: {}", e);
-        }
-        
-        fs::write(control_path, "auto")
-            .context("Failed to enable charging")?;
-        
-        debug!("Charging enabled");
-        Ok(())
+
+    /// Stop the device at `sys_path` charging with `strategy`.
+    ///
+    /// # Returns
+    /// * `Ok(action)` - What was done, to undo it later
+    /// * `Err` - The strategy does not apply to the device
+    pub fn set_charging_disabled(&self, sys_path: &str, strategy: ChargingStrategy) -> Result<ChargingAction> {
+        let sys_path = sys_path.to_string();
+        let action = match strategy {
+            ChargingStrategy::RuntimeSuspend => {
+                let control = read_attribute(&sys_path, "power/control")?;
+                let autosuspend_delay_ms = Some(read_attribute(&sys_path, "power/autosuspend_delay_ms")?);
+                write_attribute(&sys_path, "power/autosuspend_delay_ms", "0")?;
+                write_attribute(&sys_path, "power/control", "auto")?;
+                ChargingAction::RuntimeSuspend { sys_path, control, autosuspend_delay_ms }
+            }
+            ChargingStrategy::Deauthorize => {
+                write_attribute(&sys_path, "authorized", "0")?;
+                ChargingAction::Deauthorize { sys_path }
+            }
+            ChargingStrategy::Unbind => {
+                let driver = fs::canonicalize(Path::new(&sys_path).join("driver"))
+                    .with_context(|| format!("No driver bound to {}", sys_path))?;
+                write_file(&driver.join("unbind"), device_name(&sys_path)?)?;
+                ChargingAction::Unbind { sys_path, driver }
+            }
+            ChargingStrategy::PortDisable => {
+                UsbPort::for_device(&sys_path)?.set_power(false, PortSwitch::DisableAttribute)?;
+                ChargingAction::PortDisable { sys_path }
+            }
+            ChargingStrategy::HubPowerOff => {
+                UsbPort::for_device(&sys_path)?.set_power(false, PortSwitch::HubRequest)?;
+                ChargingAction::HubPowerOff { sys_path }
+            }
+        };
+
+        info!("Charging disabled with {} for device at {}", strategy, action.sys_path());
+        Ok(action)
     }
-    
-    fn disable_charging(&self, autosuspend_path: &str, control_path: &str) -> Result<()> {
-        // Disable autosuspend by setting control to suspend
-        // This prevents the device from drawing charging current
-        fs::write(control_path, "suspend")
-            .context("Failed to disable charging")?;
-        
-        debug!("Charging disabled");
+
+    /// Let the device charge again after `action`.
+    pub fn undo(&self, action: &ChargingAction) -> Result<()> {
+        match action {
+            ChargingAction::RuntimeSuspend { sys_path, control, autosuspend_delay_ms } => {
+                write_attribute(sys_path, "power/control", control)?;
+                if let Some(autosuspend_delay_ms) = autosuspend_delay_ms {
+                    write_attribute(sys_path, "power/autosuspend_delay_ms", autosuspend_delay_ms)?;
+                }
+            }
+            ChargingAction::Deauthorize { sys_path } => write_attribute(sys_path, "authorized", "1")?,
+            ChargingAction::Unbind { sys_path, driver } => write_file(&driver.join("bind"), device_name(sys_path)?)?,
+            ChargingAction::PortDisable { sys_path } => {
+                UsbPort::for_device(sys_path)?.set_power(true, PortSwitch::DisableAttribute)?;
+            }
+            ChargingAction::HubPowerOff { sys_path } => {
+                UsbPort::for_device(sys_path)?.set_power(true, PortSwitch::HubRequest)?;
+            }
+        }
+
+        info!("Charging enabled again, undid {} for device at {}", action.strategy(), action.sys_path());
         Ok(())
     }
-    
-    pub fn is_charging_enabled(&self, sys_path: &str) -> Result<bool> {
-        let control_path = format!("{}/power/control", sys_path);
-        
-        match fs::read_to_string(&control_path) {
-            Ok(content) => Ok(content.trim() == "auto"),
+
+    /// Let the device charge when it is not known what stopped it, undoing
+    /// every strategy in `strategies` that is in effect as far as possible
+    /// without knowing the settings from before.
+    pub fn set_charging_enabled(&self, sys_path: &str, strategies: &[ChargingStrategy]) -> Result<()> {
+        let mut result = Ok(());
+        for &strategy in strategies {
+            if !self.is_in_effect(sys_path, strategy) {
+                continue;
+            }
+            if let Err(e) = self.undo(&ChargingAction::assumed(sys_path, strategy)) {
+                warn!("Failed to undo {}: {:#}", strategy, e);
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    /// Whether `action` is still in effect.
+    pub fn is_applied(&self, action: &ChargingAction) -> bool {
+        self.is_in_effect(action.sys_path(), action.strategy())
+    }
+
+    /// Whether none of `strategies` currently keeps the device from charging.
+    pub fn is_charging_enabled(&self, sys_path: &str, strategies: &[ChargingStrategy]) -> bool {
+        !strategies.iter().any(|&strategy| self.is_in_effect(sys_path, strategy))
+    }
+
+    fn is_in_effect(&self, sys_path: &str, strategy: ChargingStrategy) -> bool {
+        let in_effect = match strategy {
+            ChargingStrategy::RuntimeSuspend => read_attribute(sys_path, "power/control")
+                .and_then(|control| Ok(control == "auto" && read_attribute(sys_path, "power/autosuspend_delay_ms")? == "0")),
+            ChargingStrategy::Deauthorize => read_attribute(sys_path, "authorized").map(|authorized| authorized == "0"),
+            // Still listed but without driver
+            ChargingStrategy::Unbind => Ok(Path::new(sys_path).exists() && !Path::new(sys_path).join("driver").exists()),
+            ChargingStrategy::PortDisable | ChargingStrategy::HubPowerOff => {
+                UsbPort::for_device(sys_path).and_then(|port| port.is_powered()).map(|powered| !powered)
+            }
+        };
+
+        match in_effect {
+            Ok(in_effect) => in_effect,
             Err(e) => {
-                warn!("Failed to read charging state: {}", e);
-                Ok(true) // Default to enabled for safety
+                // Default to enabled for safety
+                debug!("Cannot tell whether {} is in effect: {:#}", strategy, e);
+                false
             }
         }
     }
 }
 
+impl ChargingAction {
+    /// The least `strategy` takes to undo when nothing was recorded.
+    fn assumed(sys_path: &str, strategy: ChargingStrategy) -> Self {
+        let sys_path = sys_path.to_string();
+        match strategy {
+            ChargingStrategy::RuntimeSuspend => ChargingAction::RuntimeSuspend {
+                sys_path,
+                control: PM_CONTROL_ON.to_string(),
+                autosuspend_delay_ms: None,
+            },
+            ChargingStrategy::Deauthorize => ChargingAction::Deauthorize { sys_path },
+            ChargingStrategy::Unbind => ChargingAction::Unbind { sys_path, driver: PathBuf::from(USB_DEVICE_DRIVER) },
+            ChargingStrategy::PortDisable => ChargingAction::PortDisable { sys_path },
+            ChargingStrategy::HubPowerOff => ChargingAction::HubPowerOff { sys_path },
+        }
+    }
+
+    pub fn strategy(&self) -> ChargingStrategy {
+        match self {
+            ChargingAction::RuntimeSuspend { .. } => ChargingStrategy::RuntimeSuspend,
+            ChargingAction::Deauthorize { .. } => ChargingStrategy::Deauthorize,
+            ChargingAction::Unbind { .. } => ChargingStrategy::Unbind,
+            ChargingAction::PortDisable { .. } => ChargingStrategy::PortDisable,
+            ChargingAction::HubPowerOff { .. } => ChargingStrategy::HubPowerOff,
+        }
+    }
+
+    pub fn sys_path(&self) -> &str {
+        match self {
+            ChargingAction::RuntimeSuspend { sys_path, .. }
+            | ChargingAction::Deauthorize { sys_path }
+            | ChargingAction::Unbind { sys_path, .. }
+            | ChargingAction::PortDisable { sys_path }
+            | ChargingAction::HubPowerOff { sys_path } => sys_path,
+        }
+    }
+}
+
+fn device_name(sys_path: &str) -> Result<&str> {
+    Path::new(sys_path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("No USB device name in {}", sys_path))
+}
+
+fn read_attribute(sys_path: &str, attribute: &str) -> Result<String> {
+    let path = Path::new(sys_path).join(attribute);
+    let value = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(value.trim().to_string())
+}
+
+fn write_attribute(sys_path: &str, attribute: &str, value: &str) -> Result<()> {
+    write_file(&Path::new(sys_path).join(attribute), value)
+}
+
+fn write_file(path: &Path, value: &str) -> Result<()> {
+    if !path.exists() {
+        bail!("{} does not exist", path.display());
+    }
+    fs::write(path, value).with_context(|| format!("Failed to write {} to {}", value, path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Device 1-4.2 on port 2 of hub 1-4, bound to a fake `usb` driver.
+    struct FakeSysfs {
+        dir: tempfile::TempDir,
+    }
+
+    impl FakeSysfs {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let device = dir.path().join("devices/1-4.2");
+            fs::create_dir_all(device.join("power")).unwrap();
+            fs::create_dir_all(dir.path().join("devices/1-4/1-4:1.0/1-4-port2")).unwrap();
+            fs::create_dir_all(dir.path().join("drivers/usb")).unwrap();
+            fs::write(device.join("power/control"), "on\n").unwrap();
+            fs::write(device.join("power/autosuspend_delay_ms"), "2000\n").unwrap();
+            fs::write(device.join("authorized"), "1\n").unwrap();
+            fs::write(dir.path().join("devices/1-4/1-4:1.0/1-4-port2/disable"), "0\n").unwrap();
+            for file in ["bind", "unbind"] {
+                fs::write(dir.path().join("drivers/usb").join(file), "").unwrap();
+            }
+            std::os::unix::fs::symlink(dir.path().join("drivers/usb"), device.join("driver")).unwrap();
+            Self { dir }
+        }
+
+        fn sys_path(&self) -> String {
+            self.dir.path().join("devices/1-4.2").to_string_lossy().to_string()
+        }
+
+        fn read(&self, path: &str) -> String {
+            fs::read_to_string(self.dir.path().join(path)).unwrap().trim().to_string()
+        }
+    }
+
+    #[test]
+    fn test_strategies_and_undo() {
+        let sysfs = FakeSysfs::new();
+        let manager = PowerManager::new();
+        let sys_path = sysfs.sys_path();
+
+        // Tuned by the user or e.g. TLP
+        fs::write(sysfs.dir.path().join("devices/1-4.2/power/autosuspend_delay_ms"), "500\n").unwrap();
+        let action = manager.set_charging_disabled(&sys_path, ChargingStrategy::RuntimeSuspend).unwrap();
+        assert_eq!((sysfs.read("devices/1-4.2/power/control"), sysfs.read("devices/1-4.2/power/autosuspend_delay_ms")),
+                   ("auto".to_string(), "0".to_string()));
+        assert!(!manager.is_charging_enabled(&sys_path, &[ChargingStrategy::RuntimeSuspend]));
+        manager.undo(&action).unwrap();
+        assert_eq!(sysfs.read("devices/1-4.2/power/control"), "on");
+        assert_eq!(sysfs.read("devices/1-4.2/power/autosuspend_delay_ms"), "500");
+
+        let action = manager.set_charging_disabled(&sys_path, ChargingStrategy::Deauthorize).unwrap();
+        assert_eq!(sysfs.read("devices/1-4.2/authorized"), "0");
+        manager.undo(&action).unwrap();
+        assert_eq!(sysfs.read("devices/1-4.2/authorized"), "1");
+
+        let action = manager.set_charging_disabled(&sys_path, ChargingStrategy::Unbind).unwrap();
+        assert_eq!(sysfs.read("drivers/usb/unbind"), "1-4.2");
+        manager.undo(&action).unwrap();
+        assert_eq!(sysfs.read("drivers/usb/bind"), "1-4.2");
+
+        let action = manager.set_charging_disabled(&sys_path, ChargingStrategy::PortDisable).unwrap();
+        assert_eq!(action, ChargingAction::PortDisable { sys_path: sys_path.clone() });
+        assert_eq!(sysfs.read("devices/1-4/1-4:1.0/1-4-port2/disable"), "1");
+        manager.undo(&action).unwrap();
+        assert_eq!(sysfs.read("devices/1-4/1-4:1.0/1-4-port2/disable"), "0");
+
+        // No usbfs node for the hub
+        assert!(manager.set_charging_disabled(&sys_path, ChargingStrategy::HubPowerOff).is_err());
+    }
+
+    #[test]
+    fn test_enable_without_recorded_action() {
+        let sysfs = FakeSysfs::new();
+        let manager = PowerManager::new();
+        let sys_path = sysfs.sys_path();
+        let strategies = [ChargingStrategy::Deauthorize, ChargingStrategy::PortDisable];
+
+        assert!(manager.is_charging_enabled(&sys_path, &strategies));
+        manager.set_charging_disabled(&sys_path, ChargingStrategy::Deauthorize).unwrap();
+        manager.set_charging_disabled(&sys_path, ChargingStrategy::PortDisable).unwrap();
+        assert!(!manager.is_charging_enabled(&sys_path, &strategies));
+
+        manager.set_charging_enabled(&sys_path, &strategies).unwrap();
+        assert!(manager.is_charging_enabled(&sys_path, &strategies));
+        assert_eq!(sysfs.read("devices/1-4.2/authorized"), "1");
+        assert_eq!(sysfs.read("devices/1-4/1-4:1.0/1-4-port2/disable"), "0");
+
+        // Resumed without touching the autosuspend delay it had before
+        let strategies = [ChargingStrategy::RuntimeSuspend];
+        manager.set_charging_disabled(&sys_path, ChargingStrategy::RuntimeSuspend).unwrap();
+        manager.set_charging_enabled(&sys_path, &strategies).unwrap();
+        assert!(manager.is_charging_enabled(&sys_path, &strategies));
+        assert_eq!(sysfs.read("devices/1-4.2/power/control"), "on");
+        assert_eq!(sysfs.read("devices/1-4.2/power/autosuspend_delay_ms"), "0");
+    }
+
+    #[test]
+    fn test_action_round_trips_through_json() {
+        let action = ChargingAction::Unbind { sys_path: "/sys/bus/usb/devices/1-4.2".to_string(),
+                                              driver: PathBuf::from(USB_DEVICE_DRIVER) };
+        let json = serde_json::to_string(&action).unwrap();
+        assert!(json.contains("\"strategy\":\"unbind\""));
+        assert_eq!(serde_json::from_str::<ChargingAction>(&json).unwrap(), action);
+    }
+}
@@ -0,0 +1,262 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use log::debug;
+
+use super::hid::{BatteryLevel, BatteryStatus, ChargingStatus};
+use super::source::BatterySource;
+
+const POWER_SUPPLY_PATH: &str = "/sys/class/power_supply";
+// Batteries published by the hid-logitech-hidpp driver
+const HIDPP_BATTERY_PREFIX: &str = "hidpp_battery_";
+
+/// Reads batteries the hid-logitech-hidpp kernel driver publishes under
+/// `/sys/class/power_supply`, without talking HID++ ourselves.
+pub struct PowerSupplyManager {
+    root: PathBuf,
+    bluetooth_product_ids: Vec<u16>,
+    serial_number: Option<String>,
+}
+
+impl PowerSupplyManager {
+    pub fn new() -> Self {
+        Self::with_root(POWER_SUPPLY_PATH)
+    }
+
+    pub fn with_root(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            bluetooth_product_ids: Vec::new(),
+            serial_number: None,
+        }
+    }
+
+    /// Also match batteries of devices connected over Bluetooth with one of `product_ids`.
+    pub fn set_bluetooth_product_ids(&mut self, product_ids: Vec<u16>) {
+        self.bluetooth_product_ids = product_ids;
+    }
+
+    /// Only match the battery reporting this serial number, when it reports one.
+    pub fn set_serial_number(&mut self, serial_number: Option<String>) {
+        self.serial_number = serial_number;
+    }
+
+    /// Read the battery of the device matching `vendor_id`/`product_id`.
+    ///
+    /// # Returns
+    /// * `Ok(Some(status))` - Battery found and readable
+    /// * `Ok(None)` - The driver publishes no battery for the device, e.g.
+    ///   because it is not bound or the device is asleep
+    pub fn get_battery_level(&self, vendor_id: u16, product_id: u16) -> Result<Option<BatteryStatus>> {
+        let Some(battery) = self.find_battery(vendor_id, product_id)? else {
+            return Ok(None);
+        };
+
+        match read_battery(&battery) {
+            Ok(status) => {
+                debug!("Battery status read from {:?}: {:?}", battery, status);
+                Ok(Some(status))
+            }
+            // The attributes of a disconnected device fail to read until it reconnects
+            Err(e) => {
+                debug!("Failed to read {:?}: {:#}", battery, e);
+                Ok(None)
+            }
+        }
+    }
+
+    fn find_battery(&self, vendor_id: u16, product_id: u16) -> Result<Option<PathBuf>> {
+        let entries = match fs::read_dir(&self.root) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("Failed to read power_supply class directory"),
+        };
+
+        for entry in entries {
+            let path = entry?.path();
+            let is_hidpp_battery = path.file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with(HIDPP_BATTERY_PREFIX));
+            if !is_hidpp_battery {
+                continue;
+            }
+
+            let Some((battery_vendor, battery_product)) = hid_device_ids(&path) else {
+                continue;
+            };
+            if battery_vendor != vendor_id
+                || (battery_product != product_id && !self.bluetooth_product_ids.contains(&battery_product)) {
+                continue;
+            }
+
+            if let (Some(wanted), Some(serial_number)) = (&self.serial_number, read_attribute(&path, "serial_number")) {
+                if !wanted.eq_ignore_ascii_case(&serial_number) {
+                    debug!("Skipping {:?} with serial number {}", path, serial_number);
+                    continue;
+                }
+            }
+
+            debug!("Found battery {:?} ({}) for {:04x}:{:04x}", path,
+                   read_attribute(&path, "model_name").unwrap_or_default(), vendor_id, product_id);
+            return Ok(Some(path));
+        }
+
+        Ok(None)
+    }
+}
+
+impl BatterySource for PowerSupplyManager {
+    fn read_battery(&mut self, vendor_id: u16, product_id: u16) -> Result<Option<BatteryStatus>> {
+        self.get_battery_level(vendor_id, product_id)
+    }
+}
+
+/// Vendor and product ID from the HID device the battery hangs off, whose
+/// directory is named BBBB:VVVV:PPPP.NNNN.
+fn hid_device_ids(battery: &Path) -> Option<(u16, u16)> {
+    let device = fs::read_link(battery.join("device")).ok()?;
+    let name = device.file_name()?.to_string_lossy().to_string();
+
+    let mut parts = name.split(['.', ':']);
+    let _bus = parts.next()?;
+    let vendor_id = u16::from_str_radix(parts.next()?, 16).ok()?;
+    let product_id = u16::from_str_radix(parts.next()?, 16).ok()?;
+    Some((vendor_id, product_id))
+}
+
+fn read_battery(battery: &Path) -> Result<BatteryStatus> {
+    let status = read_attribute(battery, "status")
+        .context("Battery has no status attribute")?;
+    // Absent on devices reporting levels only
+    let percentage = read_attribute(battery, "capacity")
+        .and_then(|capacity| capacity.parse::<u8>().ok())
+        .map(|capacity| capacity.min(100));
+
+    let level = match read_attribute(battery, "capacity_level").as_deref() {
+        Some("Critical") => BatteryLevel::Critical,
+        Some("Low") => BatteryLevel::Low,
+        Some("Normal") | Some("High") => BatteryLevel::Good,
+        Some("Full") => BatteryLevel::Full,
+        _ => percentage.map_or(BatteryLevel::Unknown, BatteryLevel::from_percentage),
+    };
+
+    // The driver reports charging errors as "Not charging"
+    let charging = match status.as_str() {
+        "Charging" => ChargingStatus::Charging,
+        "Full" => ChargingStatus::Full,
+        "Not charging" => ChargingStatus::Error,
+        _ => ChargingStatus::Discharging,
+    };
+
+    Ok(BatteryStatus {
+        percentage,
+        level,
+        charging,
+        external_power: matches!(charging, ChargingStatus::Charging | ChargingStatus::Full),
+        // Microvolts
+        voltage_mv: read_attribute(battery, "voltage_now")
+            .and_then(|voltage| voltage.parse::<u32>().ok())
+            .map(|voltage| (voltage / 1000) as u16),
+    })
+}
+
+fn read_attribute(battery: &Path, name: &str) -> Option<String> {
+    fs::read_to_string(battery.join(name))
+        .ok()
+        .map(|value| value.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    struct Fixture {
+        root: tempfile::TempDir,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let root = tempfile::tempdir().unwrap();
+            fs::create_dir_all(root.path().join("devices")).unwrap();
+            Self { root }
+        }
+
+        fn add_battery(&self, name: &str, hid_device: &str, attributes: &[(&str, &str)]) {
+            let battery = self.root.path().join("class").join(name);
+            let device = self.root.path().join("devices").join(hid_device);
+            fs::create_dir_all(&battery).unwrap();
+            fs::create_dir_all(&device).unwrap();
+            symlink(&device, battery.join("device")).unwrap();
+            for (attribute, value) in attributes {
+                fs::write(battery.join(attribute), format!("{}\n", value)).unwrap();
+            }
+        }
+
+        fn manager(&self) -> PowerSupplyManager {
+            PowerSupplyManager::with_root(self.root.path().join("class"))
+        }
+    }
+
+    const MX_KEYS_MINI: [(&str, &str); 6] = [
+        ("capacity", "72"),
+        ("capacity_level", "Normal"),
+        ("status", "Charging"),
+        ("voltage_now", "3912000"),
+        ("model_name", "MX Keys Mini"),
+        ("serial_number", "1a2b3c4d"),
+    ];
+
+    #[test]
+    fn test_read_hidpp_battery() {
+        let fixture = Fixture::new();
+        fixture.add_battery("BAT0", "PNP0C0A:00", &[("capacity", "40"), ("status", "Discharging")]);
+        fixture.add_battery("hidpp_battery_0", "0003:046D:4082.0005", &[("capacity", "10"), ("status", "Discharging")]);
+        fixture.add_battery("hidpp_battery_1", "0003:046D:B369.0007", &MX_KEYS_MINI);
+
+        let status = fixture.manager().get_battery_level(0x046D, 0xB369).unwrap();
+        assert_eq!(status, Some(BatteryStatus {
+            percentage: Some(72),
+            level: BatteryLevel::Good,
+            charging: ChargingStatus::Charging,
+            external_power: true,
+            voltage_mv: Some(3912),
+        }));
+
+        assert_eq!(fixture.manager().get_battery_level(0x046D, 0xC548).unwrap(), None);
+    }
+
+    #[test]
+    fn test_match_bluetooth_product_id_and_serial_number() {
+        let fixture = Fixture::new();
+        fixture.add_battery("hidpp_battery_2", "0005:046D:B369.0009", &MX_KEYS_MINI);
+
+        let mut manager = fixture.manager();
+        assert_eq!(manager.get_battery_level(0x046D, 0xC548).unwrap(), None);
+
+        manager.set_bluetooth_product_ids(vec![0xB369]);
+        assert!(manager.get_battery_level(0x046D, 0xC548).unwrap().is_some());
+
+        manager.set_serial_number(Some("FFFFFFFF".to_string()));
+        assert_eq!(manager.get_battery_level(0x046D, 0xC548).unwrap(), None);
+        manager.set_serial_number(Some("1A2B3C4D".to_string()));
+        assert!(manager.get_battery_level(0x046D, 0xC548).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_level_only_battery() {
+        let fixture = Fixture::new();
+        fixture.add_battery("hidpp_battery_3", "0003:046D:4069.000A", &[("capacity_level", "Low"), ("status", "Not charging")]);
+
+        let status = fixture.manager().get_battery_level(0x046D, 0x4069).unwrap().unwrap();
+        assert_eq!(status.percentage, None);
+        assert_eq!(status.percentage_or_estimate(), Some(20));
+        assert_eq!(status.charging, ChargingStatus::Error);
+        assert!(!status.external_power);
+    }
+
+    #[test]
+    fn test_missing_power_supply_class() {
+        let manager = PowerSupplyManager::with_root("/nonexistent/power_supply");
+        assert_eq!(manager.get_battery_level(0x046D, 0xB369).unwrap(), None);
+    }
+}
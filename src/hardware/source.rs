@@ -0,0 +1,16 @@
+use anyhow::Result;
+
+use super::hid::BatteryStatus;
+
+/// Somewhere the battery of a device can be read from: our own HID++
+/// requests, the kernel driver or a desktop service tracking it.
+pub trait BatterySource {
+    /// Read the battery of the device matching `vendor_id`/`product_id`.
+    ///
+    /// # Returns
+    /// * `Ok(Some(status))` - Battery read
+    /// * `Ok(None)` - The source does not know the device right now, so the
+    ///   next source should be tried
+    /// * `Err` - The source failed
+    fn read_battery(&mut self, vendor_id: u16, product_id: u16) -> Result<Option<BatteryStatus>>;
+}
@@ -0,0 +1,157 @@
+#[cfg(feature = "hidapi")]
+use std::ffi::CString;
+use anyhow::Result;
+#[cfg(feature = "hidapi")]
+use anyhow::Context;
+#[cfg(feature = "hidapi")]
+use hidapi::{HidApi, HidDevice};
+#[cfg(not(feature = "hidapi"))]
+use log::warn;
+
+use crate::config::HidBackend;
+use super::hidraw::HidrawTransport;
+
+/// A HID interface as seen during enumeration, independent of the backend.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HidInterfaceInfo {
+    // hidraw node, e.g. /dev/hidraw3
+    pub path: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub product_string: Option<String>,
+    pub serial_number: Option<String>,
+    pub usage_page: u16,
+    pub usage: u16,
+    pub interface_number: i32,
+    pub bus_type: BusType,
+}
+
+/// Bus a HID interface is attached through.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BusType {
+    #[default]
+    Unknown,
+    Usb,
+    // Classic Bluetooth and Bluetooth LE alike, the kernel does not tell them apart
+    Bluetooth,
+    Other,
+}
+
+impl BusType {
+    /// From the `BUS_*` constants of linux/input.h, as reported by HIDIOCGRAWINFO.
+    pub fn from_linux_bus(bus: u32) -> Self {
+        match bus {
+            0x03 => BusType::Usb,
+            0x05 => BusType::Bluetooth,
+            _ => BusType::Other,
+        }
+    }
+}
+
+/// Enumerates HID interfaces and opens connections to them.
+pub trait HidTransport: Send {
+    /// Re-scan and list the HID interfaces currently attached.
+    fn devices(&mut self) -> Result<Vec<HidInterfaceInfo>>;
+
+    fn open(&self, path: &str) -> Result<Box<dyn HidConnection>>;
+}
+
+/// An open HID interface.
+pub trait HidConnection: Send {
+    fn write(&self, data: &[u8]) -> Result<usize>;
+
+    /// Read one input report, returns 0 when nothing arrived within `timeout_ms`.
+    fn read_timeout(&self, buf: &mut [u8], timeout_ms: i32) -> Result<usize>;
+
+    fn report_descriptor(&self) -> Result<Vec<u8>>;
+}
+
+/// Open the transport for the configured backend.
+///
+/// Builds without the `hidapi` feature fall back to the hidraw backend.
+pub fn open_transport(backend: HidBackend) -> Result<Box<dyn HidTransport>> {
+    match backend {
+        #[cfg(feature = "hidapi")]
+        HidBackend::Hidapi => Ok(Box::new(HidapiTransport::new()?)),
+        #[cfg(not(feature = "hidapi"))]
+        HidBackend::Hidapi => {
+            warn!("Built without hidapi support, using the hidraw backend");
+            Ok(Box::new(HidrawTransport::new()))
+        }
+        HidBackend::Hidraw => Ok(Box::new(HidrawTransport::new())),
+    }
+}
+
+/// Transport backed by the `hidapi` crate.
+#[cfg(feature = "hidapi")]
+pub struct HidapiTransport {
+    api: HidApi,
+}
+
+#[cfg(feature = "hidapi")]
+impl HidapiTransport {
+    pub fn new() -> Result<Self> {
+        let api = HidApi::new()
+            .context("Failed to initialize HID API")?;
+
+        Ok(Self { api })
+    }
+}
+
+#[cfg(feature = "hidapi")]
+impl HidTransport for HidapiTransport {
+    fn devices(&mut self) -> Result<Vec<HidInterfaceInfo>> {
+        self.api.refresh_devices()
+            .context("Failed to enumerate HID devices")?;
+
+        Ok(self.api
+            .device_list()
+            .map(|info| HidInterfaceInfo {
+                path: info.path().to_string_lossy().to_string(),
+                vendor_id: info.vendor_id(),
+                product_id: info.product_id(),
+                product_string: info.product_string().map(str::to_string),
+                serial_number: info.serial_number().map(str::to_string),
+                usage_page: info.usage_page(),
+                usage: info.usage(),
+                interface_number: info.interface_number(),
+                bus_type: match info.bus_type() {
+                    hidapi::BusType::Usb => BusType::Usb,
+                    hidapi::BusType::Bluetooth => BusType::Bluetooth,
+                    hidapi::BusType::Unknown => BusType::Unknown,
+                    _ => BusType::Other,
+                },
+            })
+            .collect())
+    }
+
+    fn open(&self, path: &str) -> Result<Box<dyn HidConnection>> {
+        let c_path = CString::new(path)
+            .context("Invalid HID device path")?;
+        let device = self.api.open_path(&c_path)
+            .with_context(|| format!("Failed to open HID device {}", path))?;
+
+        Ok(Box::new(device))
+    }
+}
+
+#[cfg(feature = "hidapi")]
+impl HidConnection for HidDevice {
+    fn write(&self, data: &[u8]) -> Result<usize> {
+        HidDevice::write(self, data)
+            .context("Failed to write to HID device")
+    }
+
+    fn read_timeout(&self, buf: &mut [u8], timeout_ms: i32) -> Result<usize> {
+        HidDevice::read_timeout(self, buf, timeout_ms)
+            .context("Failed to read from HID device")
+    }
+
+    fn report_descriptor(&self) -> Result<Vec<u8>> {
+        let mut descriptor = vec![0u8; hidapi::MAX_REPORT_DESCRIPTOR_SIZE];
+        let len = self.get_report_descriptor(&mut descriptor)
+            .context("Failed to read report descriptor")?;
+        descriptor.truncate(len);
+        Ok(descriptor)
+    }
+}
@@ -1,8 +1,20 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use log::debug;
 
+use super::port::UsbPort;
+
+// wHubCharacteristics logical power switching mode (USB 2.0 spec, 11.23.2.1)
+const LPSM_MASK: u16 = 0x0003;
+const LPSM_GANGED: u16 = 0x0000;
+const LPSM_INDIVIDUAL: u16 = 0x0001;
+
+// Hubs before USB 2.0 honor PortPwrCtrlMask, later ones set all of its bits
+const USB_2_0: u16 = 0x0200;
+
+const USB_DEVICES_PATH: &str = "/sys/bus/usb/devices";
+
 #[derive(Debug, Clone)]
 pub struct USBManager {
     pub bus: u8,
@@ -12,16 +24,101 @@ pub struct USBManager {
     pub sys_path: String,
 }
 
-pub struct USBDeviceManager;
+pub struct USBDeviceManager {
+    root: PathBuf,
+}
+
+/// What switching off the power of a hub port does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortPowerSwitching {
+    /// Only the port itself loses power
+    Individual,
+    /// The port keeps power until every port of the hub is switched off
+    Ganged,
+    /// Ports are always powered, switching them off is a no-op
+    NotSwitchable,
+}
+
+/// The parts of a hub class descriptor that matter for power switching.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HubDescriptor {
+    pub ports: u8,
+    pub characteristics: u16,
+    // PortPwrCtrlMask, bit N for port N, only in USB 2.0 descriptors
+    pub port_power_mask: Vec<u8>,
+}
+
+impl HubDescriptor {
+    /// Parse a USB 2.0 (0x29) or SuperSpeed (0x2A) hub descriptor, both
+    /// starting with bLength, bDescriptorType, bNbrPorts, wHubCharacteristics.
+    pub fn parse(descriptor: &[u8]) -> Result<Self> {
+        if descriptor.len() < 5 {
+            bail!("Hub descriptor too short: {} bytes", descriptor.len());
+        }
+
+        let ports = descriptor[2];
+        // DeviceRemovable, then PortPwrCtrlMask, each a bit per port plus reserved bit 0
+        let bitmap_len = ports as usize / 8 + 1;
+        let port_power_mask = match descriptor.get(7 + bitmap_len..7 + 2 * bitmap_len) {
+            Some(mask) if descriptor[1] == 0x29 => mask.to_vec(),
+            _ => Vec::new(),
+        };
+
+        Ok(Self {
+            ports,
+            characteristics: u16::from_le_bytes([descriptor[3], descriptor[4]]),
+            port_power_mask,
+        })
+    }
+
+    /// How switching off the power of `port` behaves on a hub of USB version
+    /// `usb_version` (bcdUSB). Ganged USB 1.1 hubs switch the ports set in
+    /// PortPwrCtrlMask on their own.
+    pub fn port_power_switching(&self, port: u8, usb_version: u16) -> Result<PortPowerSwitching> {
+        if port == 0 || port > self.ports {
+            bail!("Port {} does not exist, the hub has {} ports", port, self.ports);
+        }
+
+        let masked = self.port_power_mask
+            .get(port as usize / 8)
+            .is_some_and(|bits| bits & (1 << (port % 8)) != 0);
+        Ok(match self.power_switching() {
+            PortPowerSwitching::Ganged if masked && usb_version < USB_2_0 => PortPowerSwitching::Individual,
+            switching => switching,
+        })
+    }
+
+    pub fn power_switching(&self) -> PortPowerSwitching {
+        match self.characteristics & LPSM_MASK {
+            LPSM_GANGED => PortPowerSwitching::Ganged,
+            LPSM_INDIVIDUAL => PortPowerSwitching::Individual,
+            // 1X: no power switching, reserved for SuperSpeed hubs
+            _ => PortPowerSwitching::NotSwitchable,
+        }
+    }
+}
+
+impl std::fmt::Display for PortPowerSwitching {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PortPowerSwitching::Individual => write!(f, "individual"),
+            PortPowerSwitching::Ganged => write!(f, "ganged"),
+            PortPowerSwitching::NotSwitchable => write!(f, "not_switchable"),
+        }
+    }
+}
 
 impl USBDeviceManager {
     pub fn new() -> Self {
-        Self
+        Self::with_root(USB_DEVICES_PATH)
+    }
+
+    pub fn with_root(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
     }
 
     pub fn find_device(&self, vendor_id: u16, product_id: u16) -> Result<Option<USBManager>> {
-        let usb_devices_path = "/sys/bus/usb/devices";
-        let entries = fs::read_dir(usb_devices_path)
+        let entries = fs::read_dir(&self.root)
             .context("Failed to read USB devices directory")?;
 
         for entry in entries {
@@ -36,6 +133,13 @@ impl USBDeviceManager {
         Ok(None)
     }
 
+    /// How the hub port the device at `sys_path` is plugged into switches power.
+    pub fn probe_port(&self, sys_path: &str) -> Result<PortPowerSwitching> {
+        let port = UsbPort::for_device(sys_path)?;
+        let descriptor = HubDescriptor::parse(&port.read_hub_descriptor()?)?;
+        probe_port_power(&port, &descriptor)
+    }
+
     fn check_device_by_uevent(&self, path: &Path, target_vendor: u16, target_product: u16) -> Result<Option<USBManager>> {
         let uevent_path = path.join("uevent");
 
@@ -50,8 +154,7 @@ impl USBDeviceManager {
 
         // Look for PRODUCT line in format: PRODUCT=vendor_id/product_id/version
         for line in uevent_content.lines() {
-            if line.starts_with("PRODUCT=") {
-                let product_line = &line[8..]; // Remove "PRODUCT=" prefix
+            if let Some(product_line) = line.strip_prefix("PRODUCT=") {
                 let parts: Vec<&str> = product_line.split('/').collect();
 
                 if parts.len() >= 2 {
@@ -82,8 +185,8 @@ impl USBDeviceManager {
             .unwrap_or("");
 
         // Parse bus number from directory name (format: usb1, 1-1, 1-1.2, etc.)
-        let bus = if dir_name.starts_with("usb") {
-            dir_name[3..].parse().unwrap_or(0)
+        let bus = if let Some(bus) = dir_name.strip_prefix("usb") {
+            bus.parse().unwrap_or(0)
         } else {
             dir_name.chars().next().unwrap_or('0').to_digit(10).unwrap_or(0) as u8
         };
@@ -110,4 +213,66 @@ impl USBDeviceManager {
             Ok(0)
         }
     }
-}
\ No newline at end of file
+}
+/// How switching off the power of `port` behaves according to the
+/// descriptor of its hub.
+fn probe_port_power(port: &UsbPort, descriptor: &HubDescriptor) -> Result<PortPowerSwitching> {
+    // e.g. " 2.00", assume USB 2.0 so PortPwrCtrlMask is not relied upon
+    let usb_version = fs::read_to_string(port.hub_sys_path().join("version"))
+        .ok()
+        .and_then(|version| {
+            let (major, minor) = version.trim().split_once('.')?;
+            u16::from_str_radix(&format!("{}{}", major, minor), 16).ok()
+        })
+        .unwrap_or(USB_2_0);
+    debug!("Hub {} (USB {:x}.{:02x}) has {} ports, wHubCharacteristics=0x{:04x}, PortPwrCtrlMask={:02x?}",
+           port.hub_name(), usb_version >> 8, usb_version & 0xFF, descriptor.ports,
+           descriptor.characteristics, descriptor.port_power_mask);
+
+    descriptor.port_power_switching(port.port(), usb_version)
+        .with_context(|| format!("Cannot switch {}", port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hub_power_switching() {
+        // USB 2.0 hub with per-port power switching and overcurrent protection
+        let descriptor = HubDescriptor::parse(&[0x09, 0x29, 0x04, 0xE9, 0x00, 0x32, 0x64, 0x00, 0xFF]).unwrap();
+        assert_eq!(descriptor, HubDescriptor { ports: 4, characteristics: 0x00E9, port_power_mask: vec![0xFF] });
+        assert_eq!(descriptor.power_switching(), PortPowerSwitching::Individual);
+
+        let descriptor = HubDescriptor::parse(&[0x09, 0x29, 0x07, 0xE0, 0x00, 0x32, 0x64, 0x00, 0xFF]).unwrap();
+        assert_eq!(descriptor.power_switching(), PortPowerSwitching::Ganged);
+
+        // SuperSpeed hub without power switching
+        let descriptor = HubDescriptor::parse(&[0x0C, 0x2A, 0x04, 0x0A, 0x00, 0x32, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]).unwrap();
+        assert_eq!(descriptor.power_switching(), PortPowerSwitching::NotSwitchable);
+
+        assert!(HubDescriptor::parse(&[0x09, 0x29, 0x04]).is_err());
+    }
+
+    #[test]
+    fn test_probe_port_power() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("1-4")).unwrap();
+        let port = |name: &str| UsbPort::for_device(&dir.path().join(name).to_string_lossy()).unwrap();
+
+        // Ganged USB 2.0 hub, PortPwrCtrlMask all set for compatibility
+        let ganged = HubDescriptor::parse(&[0x09, 0x29, 0x04, 0xE0, 0x00, 0x32, 0x64, 0x00, 0xFF]).unwrap();
+        fs::write(dir.path().join("1-4/version"), " 2.00\n").unwrap();
+        assert_eq!(probe_port_power(&port("1-4.2"), &ganged).unwrap(), PortPowerSwitching::Ganged);
+
+        // USB 1.1 hub switching ports 1 and 3 on their own
+        let ganged = HubDescriptor::parse(&[0x09, 0x29, 0x04, 0xE0, 0x00, 0x32, 0x64, 0x00, 0x0A]).unwrap();
+        fs::write(dir.path().join("1-4/version"), " 1.10\n").unwrap();
+        assert_eq!(probe_port_power(&port("1-4.2"), &ganged).unwrap(), PortPowerSwitching::Ganged);
+        assert_eq!(probe_port_power(&port("1-4.3"), &ganged).unwrap(), PortPowerSwitching::Individual);
+
+        let individual = HubDescriptor::parse(&[0x09, 0x29, 0x04, 0xE9, 0x00, 0x32, 0x64, 0x00, 0xFF]).unwrap();
+        assert_eq!(probe_port_power(&port("1-4.4"), &individual).unwrap(), PortPowerSwitching::Individual);
+        assert!(probe_port_power(&port("1-4.5"), &individual).is_err());
+    }
+}
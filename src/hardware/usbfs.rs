@@ -0,0 +1,93 @@
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use anyhow::{Context, Result};
+
+const USBFS_PATH: &str = "/dev/bus/usb";
+
+// Milliseconds the kernel waits for a control transfer to complete
+const CONTROL_TIMEOUT_MS: u32 = 1000;
+
+// ioctl request number from linux/usbdevice_fs.h, encoded as
+// _IOWR('U', 0, struct usbdevfs_ctrltransfer)
+const USBDEVFS_CONTROL: u32 = iowr(0x00, std::mem::size_of::<CtrlTransfer>());
+
+// struct usbdevfs_ctrltransfer
+#[repr(C)]
+struct CtrlTransfer {
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    length: u16,
+    timeout: u32,
+    data: *mut libc::c_void,
+}
+
+/// A USB device opened through usbfs, for control transfers the kernel has
+/// no sysfs attribute for.
+pub struct UsbfsDevice {
+    file: File,
+}
+
+impl UsbfsDevice {
+    pub fn open(bus: u8, device: u8) -> Result<Self> {
+        let path = format!("{}/{:03}/{:03}", USBFS_PATH, bus, device);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open {}", path))?;
+
+        Ok(Self { file })
+    }
+
+    /// Host-to-device control request without data stage.
+    pub fn control_out(&self, request_type: u8, request: u8, value: u16, index: u16) -> Result<()> {
+        self.control(request_type, request, value, index, &mut [])?;
+        Ok(())
+    }
+
+    /// Device-to-host control request, returns how many bytes of `buf` were filled.
+    pub fn control_in(&self, request_type: u8, request: u8, value: u16, index: u16, buf: &mut [u8]) -> Result<usize> {
+        self.control(request_type, request, value, index, buf)
+    }
+
+    fn control(&self, request_type: u8, request: u8, value: u16, index: u16, buf: &mut [u8]) -> Result<usize> {
+        let mut transfer = CtrlTransfer {
+            request_type,
+            request,
+            value,
+            index,
+            length: buf.len() as u16,
+            timeout: CONTROL_TIMEOUT_MS,
+            data: buf.as_mut_ptr().cast(),
+        };
+        // SAFETY: USBDEVFS_CONTROL reads or writes at most `length` bytes of `data`
+        let ret = unsafe { libc::ioctl(self.file.as_raw_fd(), USBDEVFS_CONTROL as _, &mut transfer) };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error()).with_context(|| {
+                format!("Control request 0x{:02x}/0x{:02x} failed", request_type, request)
+            });
+        }
+        Ok(ret as usize)
+    }
+}
+
+const fn iowr(nr: u32, size: usize) -> u32 {
+    const IOC_READ_WRITE: u32 = 3;
+    (IOC_READ_WRITE << 30) | ((size as u32) << 16) | ((b'U' as u32) << 8) | nr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ioctl_numbers() {
+        // 64-bit layout, the data pointer is aligned after the timeout
+        #[cfg(target_pointer_width = "64")]
+        assert_eq!(USBDEVFS_CONTROL, 0xC018_5500);
+        #[cfg(target_pointer_width = "32")]
+        assert_eq!(USBDEVFS_CONTROL, 0xC010_5500);
+    }
+}
@@ -1,37 +1,51 @@
 // src/main.rs
-use anyhow::{Context, Result};
-use log::{error, info, warn};
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+use log::info;
 use std::time::Duration;
-use tokio::time::sleep;
 
+mod adapter;
 mod config;
 mod hardware;
 mod domain;
 mod logging;
 
 use config::Config;
-use domain::BatteryManager;
+use domain::{restore, BatteryManager};
 use logging::setup_logging;
-use crate::hardware::LogitechManager;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Parser)]
+#[command(version, about = "Keeps a Logitech MX Mini battery between two charge thresholds")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Manage charging until SIGTERM or SIGINT (the default)
+    Run,
+    /// Enable charging again wherever a previous run disabled it, then exit
+    Restore,
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let cli = Cli::parse();
     setup_logging()?;
     
     let config = Config::load()?;
-    info!("Starting MX Mini Battery Manager");
-    
-    /*let mut battery_manager = BatteryManager::new(config)?;
-    
-    loop {
-        match battery_manager.check_and_manage().await {
-            Ok(_) => {},
-            Err(e) => error!("Error during battery check: {}", e),
+    match cli.command.unwrap_or(Command::Run) {
+        Command::Run => {
+            info!("Starting MX Mini Battery Manager");
+            BatteryManager::new(config)?.run(POLL_INTERVAL).await
         }
-        
-        //sleep(Duration::from_secs(60)).await;
-        sleep(Duration::from_secs(10)).await;
-    }*/
-    test_solaar_adapter
+        Command::Restore => {
+            restore(&config)?;
+            info!("Charging restored");
+            Ok(())
+        }
+    }
 }
-